@@ -0,0 +1,392 @@
+//! Encoding of outgoing messages.
+//!
+//! `serde_bencoded` only encodes dictionary keys that are UTF-8
+//! strings, while the values of BEP 44 items may have any byte string
+//! keys.  Messages are serialized into a `Value` instead, and it is
+//! bencoded the same way as `serde_bencoded` does.
+use crate::bep_0044::Value;
+use serde::ser::{self, Error as _, Serialize};
+use serde_bencoded::SerError;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+/// Bencode the message.
+pub(crate) fn to_vec<T: Serialize + ?Sized>(value: &T) -> Result<Vec<u8>, SerError> {
+    Ok(to_value(value)?.map_or_else(Vec::new, |v| v.encode()))
+}
+
+/// `None` stands for nothing encoded, e.g. a missing optional field.
+fn to_value<T: Serialize + ?Sized>(value: &T) -> Result<Option<Value>, SerError> {
+    value.serialize(ValueSerializer)
+}
+
+struct ValueSerializer;
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = Option<Value>;
+    type Error = SerError;
+    type SerializeSeq = ListSerializer;
+    type SerializeTuple = ListSerializer;
+    type SerializeTupleStruct = ListSerializer;
+    type SerializeTupleVariant = ListSerializer;
+    type SerializeMap = DictSerializer;
+    type SerializeStruct = DictSerializer;
+    type SerializeStructVariant = DictSerializer;
+
+    fn serialize_bool(self, v: bool) -> Result<Self::Ok, SerError> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Self::Ok, SerError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Self::Ok, SerError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Self::Ok, SerError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Self::Ok, SerError> {
+        Ok(Some(Value::Int(v)))
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, SerError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, SerError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, SerError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Self::Ok, SerError> {
+        let v = i64::try_from(v).map_err(|_| SerError::custom("integer is too big"))?;
+        self.serialize_i64(v)
+    }
+
+    fn serialize_f32(self, _: f32) -> Result<Self::Ok, SerError> {
+        Err(SerError::FloatingPointNotSupported)
+    }
+
+    fn serialize_f64(self, _: f64) -> Result<Self::Ok, SerError> {
+        Err(SerError::FloatingPointNotSupported)
+    }
+
+    fn serialize_char(self, v: char) -> Result<Self::Ok, SerError> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Self::Ok, SerError> {
+        self.serialize_bytes(v.as_bytes())
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Self::Ok, SerError> {
+        Ok(Some(Value::Bytes(v.to_vec())))
+    }
+
+    fn serialize_none(self) -> Result<Self::Ok, SerError> {
+        Ok(None)
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<Self::Ok, SerError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Self::Ok, SerError> {
+        self.serialize_bytes(b"")
+    }
+
+    fn serialize_unit_struct(self, name: &'static str) -> Result<Self::Ok, SerError> {
+        self.serialize_str(name)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+    ) -> Result<Self::Ok, SerError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, SerError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, SerError> {
+        let mut dict = BTreeMap::new();
+        if let Some(value) = to_value(value)? {
+            dict.insert(variant.as_bytes().to_vec(), value);
+        }
+        Ok(Some(Value::Dict(dict)))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<ListSerializer, SerError> {
+        Ok(ListSerializer(Vec::with_capacity(len.unwrap_or(0))))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<ListSerializer, SerError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer, SerError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        _variant: &'static str,
+        len: usize,
+    ) -> Result<ListSerializer, SerError> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<DictSerializer, SerError> {
+        Ok(DictSerializer::default())
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<DictSerializer, SerError> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _variant_index: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<DictSerializer, SerError> {
+        Ok(DictSerializer {
+            variant: Some(variant),
+            ..Default::default()
+        })
+    }
+}
+
+struct ListSerializer(Vec<Value>);
+
+impl ListSerializer {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerError> {
+        if let Some(value) = to_value(value)? {
+            self.0.push(value);
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeSeq for ListSerializer {
+    type Ok = Option<Value>;
+    type Error = SerError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerError> {
+        Ok(Some(Value::List(self.0)))
+    }
+}
+
+impl ser::SerializeTuple for ListSerializer {
+    type Ok = Option<Value>;
+    type Error = SerError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for ListSerializer {
+    type Ok = Option<Value>;
+    type Error = SerError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for ListSerializer {
+    type Ok = Option<Value>;
+    type Error = SerError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerError> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+/// Keys are sorted by the map; entries without a value are skipped.
+#[derive(Default)]
+struct DictSerializer {
+    dict: BTreeMap<Vec<u8>, Value>,
+    key: Option<Vec<u8>>,
+    /// The dictionary is wrapped into `{variant: dict}` if set.
+    variant: Option<&'static str>,
+}
+
+impl DictSerializer {
+    fn insert<T: Serialize + ?Sized>(&mut self, key: Vec<u8>, value: &T) -> Result<(), SerError> {
+        if let Some(value) = to_value(value)? {
+            self.dict.insert(key, value);
+        }
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for DictSerializer {
+    type Ok = Option<Value>;
+    type Error = SerError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), SerError> {
+        match to_value(key)? {
+            Some(Value::Bytes(key)) => {
+                self.key = Some(key);
+                Ok(())
+            }
+            _ => Err(SerError::DictionaryKeyMustBeString),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), SerError> {
+        let key = self
+            .key
+            .take()
+            .ok_or_else(|| SerError::custom("value without a key"))?;
+        self.insert(key, value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerError> {
+        let dict = Value::Dict(self.dict);
+        Ok(Some(match self.variant {
+            Some(variant) => {
+                let mut wrapper = BTreeMap::new();
+                wrapper.insert(variant.as_bytes().to_vec(), dict);
+                Value::Dict(wrapper)
+            }
+            None => dict,
+        }))
+    }
+}
+
+impl ser::SerializeStruct for DictSerializer {
+    type Ok = Option<Value>;
+    type Error = SerError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerError> {
+        self.insert(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerError> {
+        ser::SerializeMap::end(self)
+    }
+}
+
+impl ser::SerializeStructVariant for DictSerializer {
+    type Ok = Option<Value>;
+    type Error = SerError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), SerError> {
+        self.insert(key.as_bytes().to_vec(), value)
+    }
+
+    fn end(self) -> Result<Self::Ok, SerError> {
+        ser::SerializeMap::end(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dht::{GetResponse, Message, NodeAddr, OutgoingMessage};
+    use std::borrow::Cow;
+    use std::error::Error;
+
+    #[test]
+    fn test_same_as_serde_bencoded() -> Result<(), Box<dyn Error>> {
+        const PUT: &[u8] = b"d1:ad3:casi1e2:id20:abcdefghij01234567891:k32:0123456789abcdef0123456789abcdef4:salt6:foobar3:seqi2e3:sig64:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef5:token8:aoeusnth1:vd1:ai1e1:bl0:i-2eeee1:q3:put1:t2:aa1:y1:qe";
+        let put: Message<()> = serde_bencoded::from_bytes_auto(PUT)?;
+        let msg = OutgoingMessage {
+            t: Cow::Borrowed(b"aa"),
+            ip: None,
+            ro: Some(true),
+            msg: put,
+        };
+        assert_eq!(to_vec(&msg)?, serde_bencoded::to_vec(&msg)?);
+
+        let error = OutgoingMessage::<()> {
+            t: Cow::Borrowed(b"aa"),
+            ip: Some(NodeAddr::from(&"10.0.0.1:6881".parse()?)),
+            ro: None,
+            msg: Message::E {
+                e: (201, "A Generic Error Ocurred".to_owned()),
+            },
+        };
+        assert_eq!(to_vec(&error)?, serde_bencoded::to_vec(&error)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_binary_keys() -> Result<(), Box<dyn Error>> {
+        const GET: &[u8] =
+            b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth1:vd1:\xffi1eee1:t2:aa1:y1:re";
+        let get: Message<GetResponse> = serde_bencoded::from_bytes_auto(GET)?;
+        match &get {
+            Message::R { r } => {
+                let mut dict = BTreeMap::new();
+                dict.insert(vec![0xff], Value::Int(1));
+                assert_eq!(r.v, Some(Value::Dict(dict)));
+            }
+            _ => panic!("unexpected message {:?}", get),
+        }
+        let msg = OutgoingMessage {
+            t: Cow::Borrowed(b"aa"),
+            ip: None,
+            ro: None,
+            msg: get,
+        };
+        assert!(serde_bencoded::to_vec(&msg).is_err());
+        assert_eq!(to_vec(&msg)?, GET);
+        Ok(())
+    }
+}
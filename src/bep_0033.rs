@@ -0,0 +1,159 @@
+//! DHT scrapes, as described in
+//! https://www.bittorrent.org/beps/bep_0033.html
+//!
+//! A node answering a scraping `get_peers` returns two bloom filters
+//! of the IP addresses of the peers it knows: one for seeds and one
+//! for downloaders.  Filters from several nodes are merged with
+//! bitwise or, and the number of distinct addresses is estimated from
+//! the number of zero bits.
+use serde::de::{Deserialize, Deserializer, Visitor};
+use serde::ser::{Serialize, Serializer};
+use sha1::{Digest, Sha1};
+use std::fmt;
+use std::net::IpAddr;
+
+/// Filter size in bytes.
+pub(crate) const BLOOM_FILTER_SIZE: usize = 256;
+/// Filter size in bits.
+const M: usize = BLOOM_FILTER_SIZE * 8;
+/// Number of hash functions.
+const K: usize = 2;
+
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct BloomFilter([u8; BLOOM_FILTER_SIZE]);
+
+impl BloomFilter {
+    pub(crate) fn insert(&mut self, ip: IpAddr) {
+        let hash = match ip {
+            IpAddr::V4(v4) => Sha1::digest(v4.octets()),
+            IpAddr::V6(v6) => Sha1::digest(v6.octets()),
+        };
+        for i in 0..K {
+            let index = (hash[2 * i] as usize | (hash[2 * i + 1] as usize) << 8) % M;
+            self.0[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    pub(crate) fn merge(&mut self, other: &BloomFilter) {
+        for (a, b) in self.0.iter_mut().zip(other.0.iter()) {
+            *a |= b;
+        }
+    }
+
+    /// Estimated number of distinct addresses inserted.
+    pub(crate) fn estimate(&self) -> f64 {
+        let zeros: usize = self.0.iter().map(|b| b.count_zeros() as usize).sum();
+        // A filter full of ones would give infinity.
+        let c = zeros.max(1) as f64;
+        let m = M as f64;
+        (c / m).ln() / (K as f64 * (1.0 - 1.0 / m).ln())
+    }
+}
+
+impl Default for BloomFilter {
+    fn default() -> Self {
+        Self([0; BLOOM_FILTER_SIZE])
+    }
+}
+
+impl fmt::Debug for BloomFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "BloomFilter(~{:.0})", self.estimate())
+    }
+}
+
+// Serde doesn't yet call serialize_bytes; call it manually.
+impl Serialize for BloomFilter {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+struct BloomFilterDeserializerVisitor;
+
+impl<'de> Visitor<'de> for BloomFilterDeserializerVisitor {
+    type Value = BloomFilter;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a {} bytes bloom filter", BLOOM_FILTER_SIZE)
+    }
+
+    fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        if v.len() == BLOOM_FILTER_SIZE {
+            let mut filter = BloomFilter::default();
+            filter.0.copy_from_slice(v);
+            Ok(filter)
+        } else {
+            Err(E::invalid_length(v.len(), &self))
+        }
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+        self.visit_bytes(v.as_bytes())
+    }
+}
+
+impl<'de> Deserialize<'de> for BloomFilter {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_bytes(BloomFilterDeserializerVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    fn spec_filter() -> BloomFilter {
+        let mut filter = BloomFilter::default();
+        for n in 0..=255 {
+            filter.insert(Ipv4Addr::new(192, 0, 2, n).into());
+        }
+        for n in 0..=0x3e7 {
+            filter.insert(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, n).into());
+        }
+        filter
+    }
+
+    #[test]
+    fn test_spec_estimate() {
+        let estimate = spec_filter().estimate();
+        assert!((estimate - 1224.93).abs() < 0.01, "{}", estimate);
+    }
+
+    #[test]
+    fn test_bounds() {
+        assert_eq!(BloomFilter::default().estimate(), 0.0);
+        assert!(BloomFilter([0xff; BLOOM_FILTER_SIZE])
+            .estimate()
+            .is_finite());
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut a = BloomFilter::default();
+        let mut b = BloomFilter::default();
+        for n in 0..=255 {
+            a.insert(Ipv4Addr::new(192, 0, 2, n).into());
+        }
+        for n in 0..=0x3e7 {
+            b.insert(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, n).into());
+        }
+        // The same addresses are counted once.
+        b.merge(&a);
+        a.merge(&b);
+        assert_eq!(a, spec_filter());
+        assert_eq!(b, spec_filter());
+    }
+
+    #[test]
+    fn test_serde() {
+        let filter = spec_filter();
+        let data = serde_bencoded::to_vec(&filter).unwrap();
+        assert_eq!(&data[..4], b"256:");
+        let decoded: BloomFilter = serde_bencoded::from_bytes_auto(&data).unwrap();
+        assert_eq!(decoded, filter);
+
+        assert!(serde_bencoded::from_bytes_auto::<BloomFilter>(b"3:abc").is_err());
+    }
+}
@@ -1,8 +1,18 @@
 use crate::dht::DhtId;
 use arrayvec::ArrayVec;
-use crc32c_hw;
 use rand::{CryptoRng, Rng};
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// How node ids violating BEP 42 are treated by the routing table.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) enum IdPolicy {
+    /// Nodes with invalid ids are admitted only when there is room for
+    /// them, and are the first to be evicted.
+    #[default]
+    Prefer,
+    /// Nodes with invalid ids are never admitted.
+    Require,
+}
 
 pub(crate) fn get_crc(ip: IpAddr, r: u8) -> u32 {
     match ip {
@@ -42,9 +52,46 @@ pub(crate) fn gen_self_id<R: Rng + CryptoRng>(self_ip: IpAddr, rng: &mut R) -> D
     original
 }
 
+/// Local addresses are exempt from the id restriction.
+pub(crate) fn is_exempt(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => is_exempt_v4(v4),
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_exempt_v4(v4),
+            None => {
+                let first = v6.segments()[0];
+                v6.is_loopback()
+                    // Unique local fc00::/7.
+                    || first & 0xfe00 == 0xfc00
+                    // Link local fe80::/10.
+                    || first & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+fn is_exempt_v4(ip: Ipv4Addr) -> bool {
+    // 10.0.0.0/8, 172.16.0.0/12, 192.168.0.0/16 are private.
+    ip.is_private() || ip.is_link_local() || ip.is_loopback()
+}
+
+/// Check if the id is one a node with the address may have.
+pub(crate) fn is_valid_id(id: &DhtId, ip: IpAddr) -> bool {
+    let ip = match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(IpAddr::V6(v6), IpAddr::V4),
+        v4 => v4,
+    };
+    if is_exempt(ip) {
+        return true;
+    }
+    let crc = get_crc(ip, id.0[19]).to_be_bytes();
+    id.0[0] == crc[0] && id.0[1] == crc[1] && (id.0[2] & 0xF8) == (crc[2] & 0xF8)
+}
+
 #[cfg(test)]
 mod test {
-    use std::net::IpAddr;
+    use crate::dht::DhtId;
+    use std::net::{IpAddr, Ipv6Addr};
 
     #[test]
     fn test_spec() {
@@ -62,4 +109,79 @@ mod test {
             [d.0[0], d.0[1], d.0[2] & 0xF8]
         );
     }
+
+    #[test]
+    fn test_spec_examples() {
+        let examples: [([u8; 4], &str); 5] = [
+            (
+                [124, 31, 75, 21],
+                "5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401",
+            ),
+            (
+                [21, 75, 31, 124],
+                "5a3ce9c14e7a08645677bbd1cfe7d8f956d53256",
+            ),
+            (
+                [65, 23, 51, 170],
+                "a5d43220bc8f112a3d426c84764f8c2a1150e616",
+            ),
+            (
+                [84, 124, 73, 14],
+                "1b0321dd1bb1fe518101ceef99462b947a01ff41",
+            ),
+            (
+                [43, 213, 53, 83],
+                "e56f6cbf5b7c4be0237986d5243b87aa6d51305a",
+            ),
+        ];
+        for (ip, id) in examples.iter() {
+            let ip: IpAddr = (*ip).into();
+            let id = DhtId::from_str(id).unwrap();
+            assert!(super::is_valid_id(&id, ip), "{} {}", ip, id);
+
+            // Another address, same id.
+            let other: IpAddr = [8, 8, 8, 8].into();
+            assert!(!super::is_valid_id(&id, other), "{} {}", other, id);
+        }
+    }
+
+    #[test]
+    fn test_ipv6() {
+        let mut rng = crate::dht::init_chacha();
+        let ip: IpAddr = "2001:db8::1".parse().unwrap();
+        let id = super::gen_self_id(ip, &mut rng);
+        assert!(super::is_valid_id(&id, ip));
+
+        // Only the first 64 bits are significant.
+        let same_net: IpAddr = "2001:db8::ffff".parse().unwrap();
+        assert!(super::is_valid_id(&id, same_net));
+        let other_net: IpAddr = "2001:db9::1".parse().unwrap();
+        assert!(!super::is_valid_id(&id, other_net));
+    }
+
+    #[test]
+    fn test_exempt() {
+        let id = DhtId([0; 20]);
+        for ip in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.0.26",
+            "169.254.1.1",
+            "127.0.0.1",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.0.1",
+        ]
+        .iter()
+        {
+            let ip: IpAddr = ip.parse().unwrap();
+            assert!(super::is_exempt(ip), "{}", ip);
+            assert!(super::is_valid_id(&id, ip), "{}", ip);
+        }
+        assert!(!super::is_exempt(IpAddr::V6(Ipv6Addr::new(
+            0x2001, 0xdb8, 0, 0, 0, 0, 0, 1
+        ))));
+        assert!(!super::is_exempt([172, 32, 0, 1].into()));
+    }
 }
@@ -0,0 +1,491 @@
+//! Storing arbitrary data in the DHT, as described in
+//! https://www.bittorrent.org/beps/bep_0044.html
+//!
+//! Immutable items are addressed by SHA-1 of their bencoded value.
+//! Mutable items are signed with an ed25519 key, and are addressed by
+//! SHA-1 of the public key and an optional salt.
+use crate::dht::{DhtId, ErrorKind, DHT_ID_BYTE_SIZE};
+use ed25519_dalek::{Keypair, PublicKey, Signature, Signer, Verifier};
+use serde::de::{MapAccess, SeqAccess, Visitor};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_bytes::{ByteBuf, Bytes};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Maximal size of a bencoded value.
+pub(crate) const MAX_VALUE_SIZE: usize = 1000;
+pub(crate) const MAX_SALT_SIZE: usize = 64;
+pub(crate) const PUBLIC_KEY_SIZE: usize = 32;
+pub(crate) const SIGNATURE_SIZE: usize = 64;
+/// Stored items expire after this period unless put again.
+const ITEM_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+const DEFAULT_MAX_ITEMS: usize = 10_000;
+
+// KRPC error codes of BEP 44.
+pub(crate) const VALUE_TOO_BIG: ErrorKind = 205;
+pub(crate) const INVALID_SIGNATURE: ErrorKind = 206;
+pub(crate) const SALT_TOO_BIG: ErrorKind = 207;
+pub(crate) const CAS_MISMATCH: ErrorKind = 301;
+pub(crate) const SEQ_TOO_OLD: ErrorKind = 302;
+
+/// Arbitrary bencoded value.  Dictionary keys are byte strings, like
+/// any other bencoded string.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<Value>),
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        self.encode_to(&mut buf);
+        buf
+    }
+
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            Value::Int(n) => buf.extend(format!("i{}e", n).bytes()),
+            Value::Bytes(b) => encode_bytes(b, buf),
+            Value::List(l) => {
+                buf.push(b'l');
+                for v in l {
+                    v.encode_to(buf);
+                }
+                buf.push(b'e');
+            }
+            // The map keeps the keys sorted as bencode requires.
+            Value::Dict(d) => {
+                buf.push(b'd');
+                for (k, v) in d {
+                    encode_bytes(k, buf);
+                    v.encode_to(buf);
+                }
+                buf.push(b'e');
+            }
+        }
+    }
+}
+
+fn encode_bytes(b: &[u8], buf: &mut Vec<u8>) {
+    buf.extend(format!("{}:", b.len()).bytes());
+    buf.extend_from_slice(b);
+}
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Int(n) => serializer.serialize_i64(*n),
+            Value::Bytes(b) => serializer.serialize_bytes(b),
+            Value::List(l) => serializer.collect_seq(l),
+            // `serde_bencoded` only takes string keys; `bencode` takes any.
+            Value::Dict(d) => {
+                let mut map = serializer.serialize_map(Some(d.len()))?;
+                for (k, v) in d {
+                    match std::str::from_utf8(k) {
+                        Ok(k) => map.serialize_entry(k, v)?,
+                        Err(_) => map.serialize_entry(Bytes::new(k), v)?,
+                    }
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a bencoded value")
+    }
+
+    fn visit_i64<E: serde::de::Error>(self, v: i64) -> Result<Value, E> {
+        Ok(Value::Int(v))
+    }
+
+    fn visit_u64<E: serde::de::Error>(self, v: u64) -> Result<Value, E> {
+        if v <= i64::MAX as u64 {
+            Ok(Value::Int(v as i64))
+        } else {
+            Err(E::custom("integer is too big"))
+        }
+    }
+
+    fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Value, E> {
+        Ok(Value::Bytes(v.to_vec()))
+    }
+
+    fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Value, E> {
+        Ok(Value::Bytes(v.as_bytes().to_vec()))
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut list = vec![];
+        while let Some(item) = seq.next_element()? {
+            list.push(item);
+        }
+        Ok(Value::List(list))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut dict = BTreeMap::new();
+        while let Some((k, v)) = map.next_entry::<ByteBuf, _>()? {
+            dict.insert(k.into_vec(), v);
+        }
+        Ok(Value::Dict(dict))
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+/// Target of an immutable item.
+pub(crate) fn immutable_target(v: &Value) -> DhtId {
+    let hash = Sha1::digest(v.encode());
+    let mut target = DhtId::default();
+    target.0.copy_from_slice(&hash[..DHT_ID_BYTE_SIZE]);
+    target
+}
+
+/// Target of a mutable item.
+pub(crate) fn mutable_target(k: &[u8; PUBLIC_KEY_SIZE], salt: &[u8]) -> DhtId {
+    let mut hasher = Sha1::new();
+    hasher.update(k);
+    hasher.update(salt);
+    let mut target = DhtId::default();
+    target
+        .0
+        .copy_from_slice(&hasher.finalize()[..DHT_ID_BYTE_SIZE]);
+    target
+}
+
+/// The data a mutable item signature covers: bencoded `salt`, `seq`
+/// and `v` as if they were dictionary entries.
+fn signed_data(salt: &[u8], seq: i64, v: &Value) -> Vec<u8> {
+    let mut data = vec![];
+    if !salt.is_empty() {
+        data.extend_from_slice(format!("4:salt{}:", salt.len()).as_bytes());
+        data.extend_from_slice(salt);
+    }
+    data.extend_from_slice(format!("3:seqi{}e1:v", seq).as_bytes());
+    data.extend_from_slice(&v.encode());
+    data
+}
+
+/// Mutable item signed by the owner of the public key `k`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MutableItem {
+    pub k: [u8; PUBLIC_KEY_SIZE],
+    pub sig: [u8; SIGNATURE_SIZE],
+    pub seq: i64,
+    pub salt: Vec<u8>,
+    pub v: Value,
+}
+
+impl MutableItem {
+    /// Sign the value with the keypair.
+    pub(crate) fn new(keypair: &Keypair, salt: Vec<u8>, seq: i64, v: Value) -> Self {
+        let sig = keypair.sign(&signed_data(&salt, seq, &v)).to_bytes();
+        Self {
+            k: keypair.public.to_bytes(),
+            sig,
+            seq,
+            salt,
+            v,
+        }
+    }
+
+    pub fn target(&self) -> DhtId {
+        mutable_target(&self.k, &self.salt)
+    }
+
+    /// Check the signature.
+    pub fn verify(&self) -> bool {
+        let public = match PublicKey::from_bytes(&self.k) {
+            Ok(public) => public,
+            Err(_) => return false,
+        };
+        let sig = match Signature::try_from(&self.sig[..]) {
+            Ok(sig) => sig,
+            Err(_) => return false,
+        };
+        public
+            .verify(&signed_data(&self.salt, self.seq, &self.v), &sig)
+            .is_ok()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Item {
+    Immutable(Value),
+    Mutable(MutableItem),
+}
+
+/// Reasons an item cannot be put to the DHT.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ItemError {
+    /// The bencoded value is longer than 1000 bytes.
+    ValueTooBig,
+    /// The salt of a mutable item is longer than 64 bytes.
+    SaltTooBig,
+}
+
+impl fmt::Display for ItemError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ItemError::ValueTooBig => write!(f, "value is too big"),
+            ItemError::SaltTooBig => write!(f, "salt is too big"),
+        }
+    }
+}
+
+impl std::error::Error for ItemError {}
+
+/// Reasons to refuse a mutable item.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PutError {
+    /// The `cas` doesn't match the stored `seq`.
+    CasMismatch,
+    /// The stored item is newer.
+    SeqTooOld,
+    Full,
+}
+
+/// Storage of items put with `put`.
+pub(crate) struct ItemStore {
+    items: HashMap<DhtId, (Item, Instant)>,
+    max_items: usize,
+}
+
+impl ItemStore {
+    pub(crate) fn new(max_items: usize) -> Self {
+        Self {
+            items: Default::default(),
+            max_items,
+        }
+    }
+
+    /// Store or refresh an immutable item.  Returns `false` if the
+    /// store is full.
+    pub(crate) fn put_immutable(&mut self, v: Value, now: Instant) -> bool {
+        let target = immutable_target(&v);
+        if !self.has_room(&target, now) {
+            return false;
+        }
+        self.items.insert(target, (Item::Immutable(v), now));
+        true
+    }
+
+    /// Store or update a mutable item with verified signature.  With
+    /// `cas`, a stored item is updated only if it has this `seq`; a
+    /// new item is stored regardless.
+    pub(crate) fn put_mutable(
+        &mut self,
+        item: MutableItem,
+        cas: Option<i64>,
+        now: Instant,
+    ) -> Result<(), PutError> {
+        let target = item.target();
+        let stored_seq = match self.get(&target, now) {
+            Some(Item::Mutable(stored)) => Some(stored.seq),
+            _ => None,
+        };
+        // There is nothing to compare with if no item is stored yet.
+        if let (Some(cas), Some(seq)) = (cas, stored_seq) {
+            if cas != seq {
+                return Err(PutError::CasMismatch);
+            }
+        }
+        if stored_seq.is_some_and(|seq| item.seq < seq) {
+            return Err(PutError::SeqTooOld);
+        }
+        if !self.has_room(&target, now) {
+            return Err(PutError::Full);
+        }
+        self.items.insert(target, (Item::Mutable(item), now));
+        Ok(())
+    }
+
+    pub(crate) fn get(&self, target: &DhtId, now: Instant) -> Option<&Item> {
+        self.items
+            .get(target)
+            .filter(|(_, stored)| now.saturating_duration_since(*stored) < ITEM_TTL)
+            .map(|(item, _)| item)
+    }
+
+    fn has_room(&mut self, target: &DhtId, now: Instant) -> bool {
+        if !self.items.contains_key(target) && self.items.len() >= self.max_items {
+            self.expire(now);
+        }
+        self.items.contains_key(target) || self.items.len() < self.max_items
+    }
+
+    /// Remove expired items.
+    pub(crate) fn expire(&mut self, now: Instant) {
+        self.items
+            .retain(|_, (_, stored)| now.saturating_duration_since(*stored) < ITEM_TTL);
+    }
+}
+
+impl Default for ItemStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_ITEMS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn test_spec_target() {
+        // The example from the spec.
+        let v = Value::Bytes(b"Hello World!".to_vec());
+        assert_eq!(v.encode(), b"12:Hello World!");
+        assert_eq!(
+            immutable_target(&v),
+            DhtId::from_str("e5f96f6f38320f0f33959cb4d3d656452117aadb").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_value_roundtrip() -> Result<(), Box<dyn Error>> {
+        const DATA: &[u8] = b"d1:ai-1e1:bl3:xyzi42ee1:cd0:0:ee";
+        let v: Value = serde_bencoded::from_bytes_auto(DATA)?;
+        let mut c = BTreeMap::new();
+        c.insert(vec![], Value::Bytes(vec![]));
+        let mut dict = BTreeMap::new();
+        dict.insert(b"a".to_vec(), Value::Int(-1));
+        dict.insert(
+            b"b".to_vec(),
+            Value::List(vec![Value::Bytes(b"xyz".to_vec()), Value::Int(42)]),
+        );
+        dict.insert(b"c".to_vec(), Value::Dict(c));
+        assert_eq!(v, Value::Dict(dict));
+        assert_eq!(v.encode(), DATA);
+        Ok(())
+    }
+
+    #[test]
+    fn test_value_binary_keys() -> Result<(), Box<dyn Error>> {
+        const DATA: &[u8] = b"d1:\x00i1e2:\xff\xfei2ee";
+        let v: Value = serde_bencoded::from_bytes_auto(DATA)?;
+        let mut dict = BTreeMap::new();
+        dict.insert(vec![0], Value::Int(1));
+        dict.insert(vec![0xff, 0xfe], Value::Int(2));
+        assert_eq!(v, Value::Dict(dict));
+        assert_eq!(v.encode(), DATA);
+        Ok(())
+    }
+
+    #[test]
+    fn test_store_expiry() {
+        let now = Instant::now();
+        let mut store = ItemStore::new(1);
+        let v = Value::Int(1);
+        let target = immutable_target(&v);
+
+        assert!(store.put_immutable(v.clone(), now));
+        assert_eq!(store.get(&target, now), Some(&Item::Immutable(v)));
+        assert!(!store.put_immutable(Value::Int(2), now));
+
+        assert_eq!(store.get(&target, now + ITEM_TTL), None);
+        // Expired items free space.
+        assert!(store.put_immutable(Value::Int(2), now + ITEM_TTL));
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        s.as_bytes()
+            .chunks(2)
+            .map(|c| u8::from_str_radix(std::str::from_utf8(c).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    /// The first example from the spec, with the given salt and
+    /// signature.
+    fn spec_item(salt: &[u8], sig: &str) -> MutableItem {
+        let mut k = [0; PUBLIC_KEY_SIZE];
+        k.copy_from_slice(&hex(
+            "77ff84905a91936367c01360803104f92432fcd904a43511876df5cdf3e7e548",
+        ));
+        let mut signature = [0; SIGNATURE_SIZE];
+        signature.copy_from_slice(&hex(sig));
+        MutableItem {
+            k,
+            sig: signature,
+            seq: 1,
+            salt: salt.to_vec(),
+            v: Value::Bytes(b"Hello World!".to_vec()),
+        }
+    }
+
+    #[test]
+    fn test_spec_mutable() {
+        let item = spec_item(b"", "305ac8aeb6c9c151fa120f120ea2cfb923564e11552d06a5d856091e5e853cff1260d3f39e4999684aa92eb73ffd136e6f4f3ecbfda0ce53a1608ecd7ae21f01");
+        assert_eq!(
+            signed_data(&item.salt, item.seq, &item.v),
+            b"3:seqi1e1:v12:Hello World!"
+        );
+        assert!(item.verify());
+        assert_eq!(
+            item.target(),
+            DhtId::from_str("4a533d47ec9c7d95b1ad75f576cffc641853b750").unwrap()
+        );
+
+        let mut tampered = item;
+        tampered.seq = 2;
+        assert!(!tampered.verify());
+    }
+
+    #[test]
+    fn test_spec_mutable_salt() {
+        let item = spec_item(b"foobar", "6834284b6b24c3204eb2fea824d82f88883a3d95e8b4a21b8c0ded553d17d17ddf9a8a7104b1258f30bed3787e6cb896fca78c58f8e03b5f18f14951a87d9a08");
+        assert_eq!(
+            signed_data(&item.salt, item.seq, &item.v),
+            b"4:salt6:foobar3:seqi1e1:v12:Hello World!"
+        );
+        assert!(item.verify());
+        assert_eq!(
+            item.target(),
+            DhtId::from_str("411eba73b6f087ca51a3795d9c8c938d365e32c1").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_store_mutable() {
+        let now = Instant::now();
+        let mut store = ItemStore::default();
+        let keypair = Keypair::generate(&mut crate::dht::init_chacha());
+        let item = |seq| MutableItem::new(&keypair, vec![], seq, Value::Int(seq));
+
+        // The CAS of a new item is ignored.
+        assert_eq!(store.put_mutable(item(2), Some(1), now), Ok(()));
+        assert_eq!(store.put_mutable(item(2), None, now), Ok(()));
+        assert_eq!(
+            store.put_mutable(item(1), None, now),
+            Err(PutError::SeqTooOld)
+        );
+        assert_eq!(
+            store.put_mutable(item(3), Some(1), now),
+            Err(PutError::CasMismatch)
+        );
+        assert_eq!(store.put_mutable(item(3), Some(2), now), Ok(()));
+        assert_eq!(
+            store.get(&item(3).target(), now),
+            Some(&Item::Mutable(item(3)))
+        );
+    }
+}
@@ -0,0 +1,194 @@
+//! Updating torrents via DHT mutable items, as described in
+//! https://www.bittorrent.org/beps/bep_0046.html
+//!
+//! A magnet link `magnet:?xs=urn:btpk:<public key>&s=<salt>` points to
+//! a BEP 44 mutable item whose value is a dictionary with the current
+//! info hash under the `ih` key.  The publisher updates the item
+//! whenever a new version of the torrent is produced.
+use crate::bep_0044::{Value, PUBLIC_KEY_SIZE};
+use crate::dht::{DhtId, DHT_ID_BYTE_SIZE};
+use std::collections::BTreeMap;
+use std::fmt;
+
+const MAGNET_PREFIX: &str = "magnet:?";
+const BTPK_PREFIX: &str = "urn:btpk:";
+const INFO_HASH_KEY: &[u8] = b"ih";
+
+/// Public key and salt of the mutable item from a magnet link.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MagnetLink {
+    pub k: [u8; PUBLIC_KEY_SIZE],
+    pub salt: Vec<u8>,
+}
+
+impl MagnetLink {
+    pub fn new(k: [u8; PUBLIC_KEY_SIZE], salt: Vec<u8>) -> Self {
+        Self { k, salt }
+    }
+
+    /// Parse the link; parameters other than `xs` and `s` are
+    /// ignored.
+    pub fn parse(link: &str) -> Result<Self, ParseMagnetError> {
+        let query = link
+            .strip_prefix(MAGNET_PREFIX)
+            .ok_or(ParseMagnetError::Prefix)?;
+        let mut k = None;
+        let mut salt = vec![];
+        for param in query.split('&') {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            match key {
+                "xs" => {
+                    if let Some(hex) = value.strip_prefix(BTPK_PREFIX) {
+                        let bytes = from_hex(hex)?;
+                        if bytes.len() != PUBLIC_KEY_SIZE {
+                            return Err(ParseMagnetError::KeyLength);
+                        }
+                        let mut buf = [0; PUBLIC_KEY_SIZE];
+                        buf.copy_from_slice(&bytes);
+                        k = Some(buf);
+                    }
+                }
+                "s" => salt = from_hex(value)?,
+                _ => {}
+            }
+        }
+        Ok(Self::new(k.ok_or(ParseMagnetError::NoKey)?, salt))
+    }
+}
+
+/// Failure to parse a magnet link.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseMagnetError {
+    /// Not a `magnet:?` link.
+    Prefix,
+    /// No `xs=urn:btpk:` parameter.
+    NoKey,
+    /// The public key is not 64 chars long.
+    KeyLength,
+    /// The public key or the salt is not a hex string.
+    Hex,
+}
+
+impl fmt::Display for ParseMagnetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseMagnetError::Prefix => write!(f, "expecting magnet link"),
+            ParseMagnetError::NoKey => write!(f, "expecting xs=urn:btpk: parameter"),
+            ParseMagnetError::KeyLength => write!(f, "expecting 64 char public key"),
+            ParseMagnetError::Hex => write!(f, "malformed hex"),
+        }
+    }
+}
+
+impl std::error::Error for ParseMagnetError {}
+
+impl fmt::Display for MagnetLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}xs={}", MAGNET_PREFIX, BTPK_PREFIX)?;
+        for b in &self.k {
+            write!(f, "{:02x}", b)?;
+        }
+        if !self.salt.is_empty() {
+            write!(f, "&s=")?;
+            for b in &self.salt {
+                write!(f, "{:02x}", b)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>, ParseMagnetError> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return Err(ParseMagnetError::Hex);
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| ParseMagnetError::Hex))
+        .collect()
+}
+
+/// Value of the mutable item pointing to the torrent.
+pub(crate) fn item_value(info_hash: &DhtId) -> Value {
+    let mut dict = BTreeMap::new();
+    dict.insert(INFO_HASH_KEY.to_vec(), Value::Bytes(info_hash.0.to_vec()));
+    Value::Dict(dict)
+}
+
+/// Info hash from the value of the mutable item.
+pub(crate) fn info_hash(v: &Value) -> Option<DhtId> {
+    match v {
+        Value::Dict(dict) => match dict.get(INFO_HASH_KEY) {
+            Some(Value::Bytes(ih)) if ih.len() == DHT_ID_BYTE_SIZE => {
+                let mut id = DhtId::default();
+                id.0.copy_from_slice(ih);
+                Some(id)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "8543d3e6115f0f98c944077a4493dcd543e49c739fd998550a1f614ab36ed63e";
+
+    #[test]
+    fn test_parse() {
+        let link =
+            MagnetLink::parse(&format!("magnet:?xs=urn:btpk:{}&s=0102ff&dn=example", KEY)).unwrap();
+        assert_eq!(link.k[..2], [0x85, 0x43]);
+        assert_eq!(link.salt, vec![1, 2, 0xff]);
+        assert_eq!(
+            link.to_string(),
+            format!("magnet:?xs=urn:btpk:{}&s=0102ff", KEY)
+        );
+
+        let link = MagnetLink::parse(&format!("magnet:?xs=urn:btpk:{}", KEY)).unwrap();
+        assert!(link.salt.is_empty());
+        assert_eq!(link.to_string(), format!("magnet:?xs=urn:btpk:{}", KEY));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            MagnetLink::parse(&format!("http://?xs=urn:btpk:{}", KEY)),
+            Err(ParseMagnetError::Prefix)
+        );
+        assert_eq!(
+            MagnetLink::parse("magnet:?xt=urn:btih:00"),
+            Err(ParseMagnetError::NoKey)
+        );
+        assert_eq!(
+            MagnetLink::parse("magnet:?xs=urn:btpk:8543"),
+            Err(ParseMagnetError::KeyLength)
+        );
+        assert_eq!(
+            MagnetLink::parse(&format!("magnet:?xs=urn:btpk:{}&s=0", KEY)),
+            Err(ParseMagnetError::Hex)
+        );
+        assert_eq!(
+            MagnetLink::parse(&format!("magnet:?xs=urn:btpk:{}&s=zz", KEY)),
+            Err(ParseMagnetError::Hex)
+        );
+    }
+
+    #[test]
+    fn test_item_value() {
+        let ih = DhtId(*b"mnopqrstuvwxyz123456");
+        let v = item_value(&ih);
+        assert_eq!(v.encode(), b"d2:ih20:mnopqrstuvwxyz123456e".to_vec());
+        assert_eq!(info_hash(&v), Some(ih));
+
+        assert_eq!(
+            info_hash(&Value::Bytes(b"mnopqrstuvwxyz123456".to_vec())),
+            None
+        );
+        let mut dict = BTreeMap::new();
+        dict.insert(b"ih".to_vec(), Value::Bytes(b"short".to_vec()));
+        assert_eq!(info_hash(&Value::Dict(dict)), None);
+    }
+}
@@ -3,33 +3,49 @@ extern crate rand_core;
 extern crate serde_bencoded;
 
 use std::borrow::Cow;
+use std::collections::BTreeMap;
 use std::fmt;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::fs::{self, File};
+use std::io::{self, Write};
 use std::net::IpAddr;
 use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::SocketAddr;
 use std::net::SocketAddrV4;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use fmt::Debug;
 use rand::rngs::OsRng;
 use rand::{CryptoRng, Rng, RngCore, SeedableRng};
 use rand_chacha::ChaCha20Rng;
 
+use ed25519_dalek::Keypair;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-const DHT_ID_BYTE_SIZE: usize = 160 / 8;
+use crate::bep_0033::BloomFilter;
+use crate::bep_0044::{MutableItem, Value};
+use crate::routing_table::Contact;
+
+pub(crate) const DHT_ID_BYTE_SIZE: usize = 160 / 8;
 // Standard 4 bytes IPv4 address + 2 bytes port
 const NODE_ADDR_BYTE_SIZE: usize = 6;
 const COMPACT_NODE_BYTE_SIZE: usize = DHT_ID_BYTE_SIZE + NODE_ADDR_BYTE_SIZE;
-pub(crate) const DEFAULT_STATE_PATH: &'static str = "duhast.state";
+// 16 bytes IPv6 address + 2 bytes port, BEP 32
+const NODE_ADDR6_BYTE_SIZE: usize = 18;
+const COMPACT_NODE6_BYTE_SIZE: usize = DHT_ID_BYTE_SIZE + NODE_ADDR6_BYTE_SIZE;
+pub(crate) const DEFAULT_STATE_PATH: &str = "duhast.state";
+/// Version of the state file format written.
+const STATE_VERSION: u32 = 1;
 
 type KeyBuf = [u8; DHT_ID_BYTE_SIZE];
 type NodeBuf = [u8; NODE_ADDR_BYTE_SIZE];
+type NodeBuf6 = [u8; NODE_ADDR6_BYTE_SIZE];
 type ContactIdBuf = [u8; COMPACT_NODE_BYTE_SIZE];
 
 /// 20-byte node id/torrent id.
-#[derive(Clone, Default, PartialEq, Eq, Ord, PartialOrd)]
-pub(crate) struct DhtId(pub(crate) KeyBuf);
+#[derive(Clone, Default, PartialEq, Eq, Ord, PartialOrd, Hash)]
+pub struct DhtId(pub(crate) KeyBuf);
 
 impl DhtId {
     pub(crate) fn new<R: Rng + CryptoRng>(rng: &mut R) -> Self {
@@ -38,21 +54,85 @@ impl DhtId {
         DhtId(buf)
     }
 
-    pub(crate) fn from_str(s: &str) -> Result<Self, &'static str> {
-        if s.len() == 40 {
-            let mut buf: KeyBuf = Default::default();
-            // TODO: as_bytes().chunks(2) is a dirty hack.
-            for (b, c) in buf.iter_mut().zip(
-                s.as_bytes()
-                    .chunks(2)
-                    .map(|x| std::str::from_utf8(x).unwrap()),
-            ) {
-                *b = u8::from_str_radix(c, 16).map_err(|_| "malformed hex")?;
+    /// A random id valid for the node's external address, BEP 42.
+    pub fn generate(ip: IpAddr) -> Self {
+        crate::bep_0042::gen_self_id(ip, &mut init_chacha())
+    }
+
+    /// Check the id is one a node with the address may have, BEP 42.
+    pub fn is_valid_for(&self, ip: IpAddr) -> bool {
+        crate::bep_0042::is_valid_id(self, ip)
+    }
+
+    pub(crate) fn from_str(s: &str) -> Result<Self, ParseIdError> {
+        if s.len() != 2 * DHT_ID_BYTE_SIZE {
+            return Err(ParseIdError::Length);
+        }
+        if !s.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(ParseIdError::Hex);
+        }
+        let mut buf: KeyBuf = Default::default();
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|_| ParseIdError::Hex)?;
+        }
+        Ok(DhtId(buf))
+    }
+
+    /// Kademlia XOR distance between two ids.
+    pub(crate) fn distance(&self, other: &DhtId) -> DhtId {
+        let mut buf: KeyBuf = Default::default();
+        for (b, (x, y)) in buf.iter_mut().zip(self.0.iter().zip(other.0.iter())) {
+            *b = x ^ y;
+        }
+        DhtId(buf)
+    }
+
+    /// Number of leading zero bits of the id, i.e. length of common
+    /// prefix when the id is a distance.
+    pub(crate) fn leading_zeros(&self) -> u32 {
+        let mut count = 0;
+        for b in &self.0 {
+            count += b.leading_zeros();
+            if *b != 0 {
+                break;
             }
-            Ok(DhtId(buf))
-        } else {
-            Err("expecting 40 char string")
         }
+        count
+    }
+}
+
+/// Failure to parse a node id from hex.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseIdError {
+    /// Not 40 chars long.
+    Length,
+    /// Not a hex string.
+    Hex,
+}
+
+impl fmt::Display for ParseIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseIdError::Length => write!(f, "expecting 40 char string"),
+            ParseIdError::Hex => write!(f, "malformed hex"),
+        }
+    }
+}
+
+impl std::error::Error for ParseIdError {}
+
+/// Parse 40 hex digits.
+impl std::str::FromStr for DhtId {
+    type Err = ParseIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DhtId::from_str(s)
+    }
+}
+
+impl From<[u8; DHT_ID_BYTE_SIZE]> for DhtId {
+    fn from(buf: [u8; DHT_ID_BYTE_SIZE]) -> Self {
+        DhtId(buf)
     }
 }
 
@@ -81,14 +161,56 @@ impl Serialize for DhtId {
     }
 }
 
-/// Packed IPv4 + port address.
+/// Packed IPv4 or IPv6 + port address.
 #[derive(Clone, Debug, PartialEq, Eq)]
-pub(crate) struct NodeAddr(NodeBuf);
+pub(crate) enum NodeAddr {
+    V4(NodeBuf),
+    V6(NodeBuf6),
+}
+
+impl NodeAddr {
+    pub(crate) fn socket_addr(&self) -> SocketAddr {
+        match self {
+            NodeAddr::V4(buf) => {
+                let mut ip = [0u8; 4];
+                ip.copy_from_slice(&buf[..4]);
+                (Ipv4Addr::from(ip), u16::from_be_bytes([buf[4], buf[5]])).into()
+            }
+            NodeAddr::V6(buf) => {
+                let mut ip = [0u8; 16];
+                ip.copy_from_slice(&buf[..16]);
+                (Ipv6Addr::from(ip), u16::from_be_bytes([buf[16], buf[17]])).into()
+            }
+        }
+    }
+}
+
+impl From<&SocketAddr> for NodeAddr {
+    fn from(addr: &SocketAddr) -> Self {
+        match addr {
+            SocketAddr::V4(v4) => {
+                let mut buf: NodeBuf = Default::default();
+                buf[..4].copy_from_slice(&v4.ip().octets());
+                buf[4..].copy_from_slice(&v4.port().to_be_bytes());
+                NodeAddr::V4(buf)
+            }
+            SocketAddr::V6(v6) => {
+                let mut buf: NodeBuf6 = Default::default();
+                buf[..16].copy_from_slice(&v6.ip().octets());
+                buf[16..].copy_from_slice(&v6.port().to_be_bytes());
+                NodeAddr::V6(buf)
+            }
+        }
+    }
+}
 
 // Serde doesn't yet call serialize_bytes; call it manually.
 impl Serialize for NodeAddr {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        serializer.serialize_bytes(&self.0)
+        match self {
+            NodeAddr::V4(buf) => serializer.serialize_bytes(buf),
+            NodeAddr::V6(buf) => serializer.serialize_bytes(buf),
+        }
     }
 }
 
@@ -98,16 +220,26 @@ impl<'de> serde::de::Visitor<'de> for NodeAddrDeserializerVisitor {
     type Value = NodeAddr;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        write!(formatter, "{} bytes", NODE_ADDR_BYTE_SIZE)
+        write!(
+            formatter,
+            "{} or {} bytes",
+            NODE_ADDR_BYTE_SIZE, NODE_ADDR6_BYTE_SIZE
+        )
     }
 
     fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
-        if v.len() == NODE_ADDR_BYTE_SIZE {
-            let mut buf: NodeBuf = Default::default();
-            &buf.copy_from_slice(&v[..]);
-            Ok(NodeAddr(buf))
-        } else {
-            Err(E::invalid_length(v.len(), &"6 bytes"))
+        match v.len() {
+            NODE_ADDR_BYTE_SIZE => {
+                let mut buf: NodeBuf = Default::default();
+                buf.copy_from_slice(v);
+                Ok(NodeAddr::V4(buf))
+            }
+            NODE_ADDR6_BYTE_SIZE => {
+                let mut buf: NodeBuf6 = Default::default();
+                buf.copy_from_slice(v);
+                Ok(NodeAddr::V6(buf))
+            }
+            _ => Err(E::invalid_length(v.len(), &"6 or 18 bytes")),
         }
     }
 
@@ -132,10 +264,10 @@ impl DhtContactId {
         let mut buf: ContactIdBuf = Default::default();
 
         let mut slice = &mut buf[..];
-        slice.write(&dht_id.0).unwrap();
-        slice.write(&socket_addr.ip().octets()).unwrap();
+        slice.write_all(&dht_id.0).unwrap();
+        slice.write_all(&socket_addr.ip().octets()).unwrap();
         // be is Big Endian, the Network Byte Order
-        slice.write(&socket_addr.port().to_be_bytes()).unwrap();
+        slice.write_all(&socket_addr.port().to_be_bytes()).unwrap();
 
         debug_assert!(slice.is_empty());
 
@@ -161,7 +293,7 @@ impl<'de> serde::de::Visitor<'de> for DhtIdDeserializerVisitor {
     fn visit_bytes<E: serde::de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
         if v.len() == DHT_ID_BYTE_SIZE {
             let mut buf: KeyBuf = Default::default();
-            &buf.copy_from_slice(&v[..]);
+            buf.copy_from_slice(v);
             Ok(DhtId(buf))
         } else {
             Err(E::invalid_length(v.len(), &"20 bytes"))
@@ -183,7 +315,7 @@ impl<'de> Deserialize<'de> for DhtId {
 #[derive(Debug)]
 pub(crate) struct CompactNode {
     pub(crate) id: DhtId,
-    pub(crate) ip: Ipv4Addr,
+    pub(crate) ip: IpAddr,
     pub(crate) port: u16,
 }
 
@@ -192,10 +324,29 @@ impl CompactNode {
         assert!(buf.len() == COMPACT_NODE_BYTE_SIZE);
         let mut id: DhtId = Default::default();
         id.0.copy_from_slice(&buf[..20]);
-        let ip = Ipv4Addr::new(buf[20], buf[21], buf[22], buf[23]);
-        let port = u16::from_le_bytes([buf[24], buf[25]]);
+        let ip = Ipv4Addr::new(buf[20], buf[21], buf[22], buf[23]).into();
+        // Network byte order.
+        let port = u16::from_be_bytes([buf[24], buf[25]]);
         Self { id, ip, port }
     }
+
+    fn unpack6(buf: &[u8]) -> Self {
+        assert!(buf.len() == COMPACT_NODE6_BYTE_SIZE);
+        let mut id: DhtId = Default::default();
+        id.0.copy_from_slice(&buf[..20]);
+        let mut ip = [0u8; 16];
+        ip.copy_from_slice(&buf[20..36]);
+        let port = u16::from_be_bytes([buf[36], buf[37]]);
+        Self {
+            id,
+            ip: Ipv6Addr::from(ip).into(),
+            port,
+        }
+    }
+
+    pub(crate) fn socket_addr(&self) -> SocketAddr {
+        (self.ip, self.port).into()
+    }
 }
 
 #[derive(PartialEq, Eq)]
@@ -207,6 +358,23 @@ impl<'msg> CompactNodesList<'msg> {
             .chunks(COMPACT_NODE_BYTE_SIZE)
             .map(CompactNode::unpack)
     }
+
+    pub(crate) fn into_owned(self) -> CompactNodesList<'static> {
+        CompactNodesList(Cow::Owned(self.0.into_owned()))
+    }
+}
+
+impl CompactNodesList<'static> {
+    /// Pack contacts; only IPv4 ones are included.
+    pub(crate) fn from_contacts<'a, I: IntoIterator<Item = &'a Contact>>(contacts: I) -> Self {
+        let mut buf = vec![];
+        for contact in contacts {
+            if let SocketAddr::V4(addr) = contact.addr {
+                buf.extend_from_slice(&DhtContactId::new(&contact.id, &addr).0);
+            }
+        }
+        CompactNodesList(Cow::Owned(buf))
+    }
 }
 
 impl Debug for CompactNodesList<'_> {
@@ -223,28 +391,31 @@ impl<'msg> Serialize for CompactNodesList<'msg> {
     }
 }
 
-struct CompactNodesListDeserializerVisitor;
+// Packed nodes of `node_size` bytes each.
+struct CompactNodesListDeserializerVisitor {
+    node_size: usize,
+}
 
 impl<'de> serde::de::Visitor<'de> for CompactNodesListDeserializerVisitor {
-    type Value = CompactNodesList<'de>;
+    type Value = Cow<'de, [u8]>;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        write!(formatter, "divisible by 26 bytes")
+        write!(formatter, "divisible by {} bytes", self.node_size)
     }
 
     fn visit_borrowed_bytes<E: serde::de::Error>(self, v: &'de [u8]) -> Result<Self::Value, E> {
-        if v.len() % COMPACT_NODE_BYTE_SIZE == 0 {
-            Ok(CompactNodesList(Cow::Borrowed(v)))
+        if v.len() % self.node_size == 0 {
+            Ok(Cow::Borrowed(v))
         } else {
-            Err(E::invalid_length(v.len(), &"divisible by 26 bytes"))
+            Err(E::invalid_length(v.len(), &self))
         }
     }
 
     fn visit_byte_buf<E: serde::de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
-        if v.len() % COMPACT_NODE_BYTE_SIZE == 0 {
-            Ok(CompactNodesList(Cow::Owned(v)))
+        if v.len() % self.node_size == 0 {
+            Ok(Cow::Owned(v))
         } else {
-            Err(E::invalid_length(v.len(), &"divisible by 26 bytes"))
+            Err(E::invalid_length(v.len(), &self))
         }
     }
 
@@ -256,10 +427,124 @@ impl<'de> serde::de::Visitor<'de> for CompactNodesListDeserializerVisitor {
 // Serde doesn't yet call serialize_bytes; call it manually.
 impl<'de: 'a, 'a> Deserialize<'de> for CompactNodesList<'a> {
     fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
-        deserializer.deserialize_bytes(CompactNodesListDeserializerVisitor)
+        deserializer
+            .deserialize_bytes(CompactNodesListDeserializerVisitor {
+                node_size: COMPACT_NODE_BYTE_SIZE,
+            })
+            .map(CompactNodesList)
+    }
+}
+
+/// IPv6 nodes list, BEP 32.
+#[derive(PartialEq, Eq)]
+pub(crate) struct CompactNodes6List<'msg>(Cow<'msg, [u8]>);
+
+impl<'msg> CompactNodes6List<'msg> {
+    pub(crate) fn iter(&'msg self) -> impl Iterator<Item = CompactNode> + 'msg {
+        self.0
+            .chunks(COMPACT_NODE6_BYTE_SIZE)
+            .map(CompactNode::unpack6)
+    }
+
+    pub(crate) fn into_owned(self) -> CompactNodes6List<'static> {
+        CompactNodes6List(Cow::Owned(self.0.into_owned()))
+    }
+}
+
+impl CompactNodes6List<'static> {
+    /// Pack contacts; only IPv6 ones are included.
+    pub(crate) fn from_contacts<'a, I: IntoIterator<Item = &'a Contact>>(contacts: I) -> Self {
+        let mut buf = vec![];
+        for contact in contacts {
+            if let SocketAddr::V6(addr) = contact.addr {
+                buf.extend_from_slice(&contact.id.0);
+                buf.extend_from_slice(&addr.ip().octets());
+                buf.extend_from_slice(&addr.port().to_be_bytes());
+            }
+        }
+        CompactNodes6List(Cow::Owned(buf))
+    }
+}
+
+impl Debug for CompactNodes6List<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let data: Vec<_> = self.iter().collect();
+        Debug::fmt(&data[..], f)
+    }
+}
+
+impl<'msg> Serialize for CompactNodes6List<'msg> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+// Serde doesn't yet call serialize_bytes; call it manually.
+impl<'de: 'a, 'a> Deserialize<'de> for CompactNodes6List<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer
+            .deserialize_bytes(CompactNodesListDeserializerVisitor {
+                node_size: COMPACT_NODE6_BYTE_SIZE,
+            })
+            .map(CompactNodes6List)
+    }
+}
+
+/// Packed info hashes of `sample_infohashes` reply, BEP 51.
+#[derive(PartialEq, Eq)]
+pub(crate) struct InfoHashList<'msg>(Cow<'msg, [u8]>);
+
+impl<'msg> InfoHashList<'msg> {
+    pub(crate) fn iter(&'msg self) -> impl Iterator<Item = DhtId> + 'msg {
+        self.0.chunks(DHT_ID_BYTE_SIZE).map(|chunk| {
+            let mut id = DhtId::default();
+            id.0.copy_from_slice(chunk);
+            id
+        })
+    }
+
+    pub(crate) fn into_owned(self) -> InfoHashList<'static> {
+        InfoHashList(Cow::Owned(self.0.into_owned()))
+    }
+}
+
+impl InfoHashList<'static> {
+    pub(crate) fn from_ids<'a, I: IntoIterator<Item = &'a DhtId>>(ids: I) -> Self {
+        let mut buf = vec![];
+        for id in ids {
+            buf.extend_from_slice(&id.0);
+        }
+        InfoHashList(Cow::Owned(buf))
+    }
+}
+
+impl Debug for InfoHashList<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let data: Vec<_> = self.iter().collect();
+        Debug::fmt(&data[..], f)
+    }
+}
+
+impl<'msg> Serialize for InfoHashList<'msg> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de: 'a, 'a> Deserialize<'de> for InfoHashList<'a> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer
+            .deserialize_bytes(CompactNodesListDeserializerVisitor {
+                node_size: DHT_ID_BYTE_SIZE,
+            })
+            .map(InfoHashList)
     }
 }
 
+/// Values of the `want` key, BEP 32.
+pub(crate) const WANT_V4: &str = "n4";
+pub(crate) const WANT_V6: &str = "n6";
+
 #[derive(Deserialize, Serialize, Debug, Eq, PartialEq)]
 pub(crate) struct PingQuery {
     pub(crate) id: DhtId,
@@ -269,12 +554,26 @@ pub(crate) struct PingQuery {
 pub(crate) struct FindNodeQuery {
     pub(crate) id: DhtId,
     pub(crate) target: DhtId,
+    pub(crate) want: Option<Vec<String>>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Eq, PartialEq)]
 pub(crate) struct GetPeersQuery {
     pub(crate) id: DhtId,
     pub(crate) info_hash: DhtId,
+    pub(crate) want: Option<Vec<String>>,
+    /// Return only peers that are not seeds, BEP 33.
+    pub(crate) noseed: Option<u8>,
+    /// Return bloom filters of seeds and downloaders instead of
+    /// peers, BEP 33.
+    pub(crate) scrape: Option<u8>,
+}
+
+/// BEP 51 `sample_infohashes`.
+#[derive(Deserialize, Serialize, Debug, Eq, PartialEq)]
+pub(crate) struct SampleInfohashesQuery {
+    pub(crate) id: DhtId,
+    pub(crate) target: DhtId,
 }
 
 #[derive(Deserialize, Serialize, Debug, Eq, PartialEq)]
@@ -285,6 +584,86 @@ pub(crate) struct AnnouncePeerQuery<'msg> {
     pub(crate) token: Cow<'msg, [u8]>,
     pub(crate) port: u16,
     pub(crate) implied_port: u8,
+    /// The announcing peer is a seed, BEP 33.
+    pub(crate) seed: Option<u8>,
+}
+
+/// BEP 44 `get`.  With `seq`, a mutable item is returned only if
+/// it is newer.
+#[derive(Deserialize, Serialize, Debug, Eq, PartialEq)]
+pub(crate) struct GetQuery {
+    pub(crate) id: DhtId,
+    pub(crate) target: DhtId,
+    pub(crate) seq: Option<i64>,
+}
+
+/// BEP 44 `put`.  The item is mutable if `k` is present.
+#[derive(Deserialize, Serialize, Debug, Eq, PartialEq)]
+pub(crate) struct PutQuery<'msg> {
+    pub(crate) id: DhtId,
+    #[serde(borrow, with = "serde_bytes")]
+    pub(crate) token: Cow<'msg, [u8]>,
+    pub(crate) v: Value,
+    #[serde(
+        borrow,
+        default,
+        with = "serde_bytes",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) k: Option<Cow<'msg, [u8]>>,
+    #[serde(
+        borrow,
+        default,
+        with = "serde_bytes",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) sig: Option<Cow<'msg, [u8]>>,
+    pub(crate) seq: Option<i64>,
+    #[serde(
+        borrow,
+        default,
+        with = "serde_bytes",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) salt: Option<Cow<'msg, [u8]>>,
+    pub(crate) cas: Option<i64>,
+}
+
+impl<'msg> PutQuery<'msg> {
+    pub(crate) fn immutable(id: DhtId, token: Cow<'msg, [u8]>, v: Value) -> Self {
+        Self {
+            id,
+            token,
+            v,
+            k: None,
+            sig: None,
+            seq: None,
+            salt: None,
+            cas: None,
+        }
+    }
+
+    pub(crate) fn mutable(
+        id: DhtId,
+        token: Cow<'msg, [u8]>,
+        item: MutableItem,
+        cas: Option<i64>,
+    ) -> Self {
+        Self {
+            id,
+            token,
+            v: item.v,
+            k: Some(Cow::Owned(item.k.to_vec())),
+            sig: Some(Cow::Owned(item.sig.to_vec())),
+            seq: Some(item.seq),
+            salt: if item.salt.is_empty() {
+                None
+            } else {
+                Some(Cow::Owned(item.salt))
+            },
+            cas,
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Eq, PartialEq)]
@@ -298,36 +677,200 @@ pub(crate) enum Query<'msg> {
     GetPeers(GetPeersQuery),
     #[serde(borrow, rename = "announce_peer")]
     AnnouncePeer(AnnouncePeerQuery<'msg>),
+    #[serde(rename = "sample_infohashes")]
+    SampleInfohashes(SampleInfohashesQuery),
+    #[serde(rename = "get")]
+    Get(GetQuery),
+    #[serde(borrow, rename = "put")]
+    Put(PutQuery<'msg>),
+}
+
+/// Method of a query, telling the type of the response.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum QueryKind {
+    Ping,
+    FindNode,
+    GetPeers,
+    AnnouncePeer,
+    SampleInfohashes,
+    Get,
+    Put,
+}
+
+impl Query<'_> {
+    pub(crate) fn kind(&self) -> QueryKind {
+        match self {
+            Query::Ping(_) => QueryKind::Ping,
+            Query::FindNode(_) => QueryKind::FindNode,
+            Query::GetPeers(_) => QueryKind::GetPeers,
+            Query::AnnouncePeer(_) => QueryKind::AnnouncePeer,
+            Query::SampleInfohashes(_) => QueryKind::SampleInfohashes,
+            Query::Get(_) => QueryKind::Get,
+            Query::Put(_) => QueryKind::Put,
+        }
+    }
+
+    /// Id of the querying node.
+    pub(crate) fn id(&self) -> &DhtId {
+        match self {
+            Query::Ping(q) => &q.id,
+            Query::FindNode(q) => &q.id,
+            Query::GetPeers(q) => &q.id,
+            Query::AnnouncePeer(q) => &q.id,
+            Query::SampleInfohashes(q) => &q.id,
+            Query::Get(q) => &q.id,
+            Query::Put(q) => &q.id,
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, PartialEq)]
 pub(crate) struct PingResponse {
-    id: DhtId,
+    pub(crate) id: DhtId,
 }
 
 #[derive(Deserialize, Serialize, Debug, Eq, PartialEq)]
 pub(crate) struct FindNodeResponse<'msg> {
     pub(crate) id: DhtId,
     #[serde(borrow)]
-    pub(crate) nodes: CompactNodesList<'msg>,
+    pub(crate) nodes: Option<CompactNodesList<'msg>>,
+    #[serde(borrow)]
+    pub(crate) nodes6: Option<CompactNodes6List<'msg>>,
+}
+
+impl FindNodeResponse<'_> {
+    pub(crate) fn into_owned(self) -> FindNodeResponse<'static> {
+        FindNodeResponse {
+            id: self.id,
+            nodes: self.nodes.map(CompactNodesList::into_owned),
+            nodes6: self.nodes6.map(CompactNodes6List::into_owned),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
 pub(crate) struct GetPeersResponse<'msg> {
     pub(crate) id: DhtId,
-    #[serde(borrow)]
+    #[serde(borrow, with = "serde_bytes")]
     pub(crate) token: Cow<'msg, [u8]>,
     pub(crate) values: Option<Vec<NodeAddr>>,
     #[serde(borrow)]
     pub(crate) nodes: Option<CompactNodesList<'msg>>,
+    #[serde(borrow)]
+    pub(crate) nodes6: Option<CompactNodes6List<'msg>>,
+    /// Seeds of the scraped torrent, BEP 33.
+    #[serde(rename = "BFsd")]
+    pub(crate) bf_sd: Option<BloomFilter>,
+    /// Downloaders of the scraped torrent, BEP 33.
+    #[serde(rename = "BFpe")]
+    pub(crate) bf_pe: Option<BloomFilter>,
+}
+
+impl GetPeersResponse<'_> {
+    pub(crate) fn into_owned(self) -> GetPeersResponse<'static> {
+        GetPeersResponse {
+            id: self.id,
+            token: Cow::Owned(self.token.into_owned()),
+            values: self.values,
+            nodes: self.nodes.map(CompactNodesList::into_owned),
+            nodes6: self.nodes6.map(CompactNodes6List::into_owned),
+            bf_sd: self.bf_sd,
+            bf_pe: self.bf_pe,
+        }
+    }
+}
+
+/// `interval` is the number of seconds the requester should wait
+/// before querying the node again, and `num` is the number of info
+/// hashes the node has.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub(crate) struct SampleInfohashesResponse<'msg> {
+    pub(crate) id: DhtId,
+    pub(crate) interval: u32,
+    pub(crate) num: u32,
+    #[serde(borrow)]
+    pub(crate) samples: InfoHashList<'msg>,
+    #[serde(borrow)]
+    pub(crate) nodes: Option<CompactNodesList<'msg>>,
+    #[serde(borrow)]
+    pub(crate) nodes6: Option<CompactNodes6List<'msg>>,
+}
+
+impl SampleInfohashesResponse<'_> {
+    pub(crate) fn into_owned(self) -> SampleInfohashesResponse<'static> {
+        SampleInfohashesResponse {
+            id: self.id,
+            interval: self.interval,
+            num: self.num,
+            samples: self.samples.into_owned(),
+            nodes: self.nodes.map(CompactNodesList::into_owned),
+            nodes6: self.nodes6.map(CompactNodes6List::into_owned),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Eq, PartialEq)]
 pub(crate) struct AnnouncePeerResponse {
-    id: DhtId,
+    pub(crate) id: DhtId,
 }
 
-type ErrorKind = u32;
+/// Mutable items are returned with `k`, `sig` and `seq`.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq)]
+pub(crate) struct GetResponse<'msg> {
+    pub(crate) id: DhtId,
+    #[serde(borrow, with = "serde_bytes")]
+    pub(crate) token: Cow<'msg, [u8]>,
+    pub(crate) v: Option<Value>,
+    #[serde(
+        borrow,
+        default,
+        with = "serde_bytes",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) k: Option<Cow<'msg, [u8]>>,
+    #[serde(
+        borrow,
+        default,
+        with = "serde_bytes",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub(crate) sig: Option<Cow<'msg, [u8]>>,
+    pub(crate) seq: Option<i64>,
+    #[serde(borrow)]
+    pub(crate) nodes: Option<CompactNodesList<'msg>>,
+    #[serde(borrow)]
+    pub(crate) nodes6: Option<CompactNodes6List<'msg>>,
+}
+
+impl GetResponse<'_> {
+    pub(crate) fn into_owned(self) -> GetResponse<'static> {
+        let owned = |b: Cow<[u8]>| Cow::Owned(b.into_owned());
+        GetResponse {
+            id: self.id,
+            token: owned(self.token),
+            v: self.v,
+            k: self.k.map(owned),
+            sig: self.sig.map(owned),
+            seq: self.seq,
+            nodes: self.nodes.map(CompactNodesList::into_owned),
+            nodes6: self.nodes6.map(CompactNodes6List::into_owned),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Eq, PartialEq)]
+pub(crate) struct PutResponse {
+    pub(crate) id: DhtId,
+}
+
+pub(crate) type ErrorKind = u32;
+
+// KRPC error codes.
+#[cfg(test)]
+pub(crate) const GENERIC_ERROR: ErrorKind = 201;
+pub(crate) const SERVER_ERROR: ErrorKind = 202;
+pub(crate) const PROTOCOL_ERROR: ErrorKind = 203;
+pub(crate) const METHOD_UNKNOWN: ErrorKind = 204;
 
 #[derive(Serialize, Deserialize, Debug, Eq, PartialEq)]
 #[serde(tag = "y")]
@@ -350,45 +893,271 @@ pub(crate) struct IncomingMessage<'msg> {
     #[serde(borrow, with = "serde_bytes")]
     pub(crate) t: &'msg [u8],
     pub(crate) ro: Option<bool>,
+    /// Our address as seen by the responder, BEP 42.
+    pub(crate) ip: Option<NodeAddr>,
 }
 
 #[derive(Serialize, Debug)]
 pub(crate) struct OutgoingMessage<'msg, R> {
     #[serde(borrow, with = "serde_bytes")]
     pub(crate) t: Cow<'msg, [u8]>,
+    /// The requester's address, sent in replies.
+    pub(crate) ip: Option<NodeAddr>,
+    /// Set in queries of a read-only node, BEP 43.
+    pub(crate) ro: Option<bool>,
     #[serde(borrow, flatten)]
     pub(crate) msg: Message<'msg, R>,
 }
 
+/// Failure to load or save the state file.
+#[derive(Debug)]
+pub(crate) enum StateError {
+    Io(io::Error),
+    Decode(serde_bencoded::DeError),
+    Encode(serde_bencoded::SerError),
+    /// The file is written by a newer version.
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::Io(e) => write!(f, "{}", e),
+            StateError::Decode(e) => write!(f, "malformed state: {}", e),
+            StateError::Encode(e) => write!(f, "cannot encode state: {}", e),
+            StateError::UnsupportedVersion(v) => write!(f, "unsupported state version {}", v),
+        }
+    }
+}
+
+impl std::error::Error for StateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StateError::Io(e) => Some(e),
+            StateError::Decode(e) => Some(e),
+            StateError::Encode(e) => Some(e),
+            StateError::UnsupportedVersion(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for StateError {
+    fn from(e: io::Error) -> Self {
+        StateError::Io(e)
+    }
+}
+
+impl From<StateError> for io::Error {
+    fn from(e: StateError) -> Self {
+        match e {
+            StateError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize)]
 pub(crate) struct Config {
+    /// Format version; files without it are version 0.
+    #[serde(default)]
+    version: u32,
     pub(crate) dht_id: DhtId,
-    peers: Vec<String>, // String is a stub here.
+    /// `host:port` addresses of the nodes to bootstrap from.
+    pub(crate) peers: Vec<String>,
+    /// Contacts of the routing tables in the compact node info format,
+    /// to bootstrap from when the bootstrap nodes don't respond.
+    #[serde(default, with = "serde_bytes")]
+    nodes: Vec<u8>,
+    #[serde(default, with = "serde_bytes")]
+    nodes6: Vec<u8>,
+    /// Unix times the contacts were last seen, IPv4 ones first.
+    #[serde(default)]
+    seen: Vec<u64>,
+    /// Ed25519 keypair for BEP 44 mutable items; empty in old state
+    /// files.
+    #[serde(default, with = "serde_bytes")]
+    keypair: Vec<u8>,
+    /// Node ids of the bound addresses, BEP 45.
+    #[serde(default)]
+    ids: BTreeMap<String, DhtId>,
 }
 
 impl Config {
     pub(crate) fn new<R: Rng + CryptoRng>(rng: &mut R, self_ip: IpAddr) -> Self {
         Config {
+            version: STATE_VERSION,
             dht_id: crate::bep_0042::gen_self_id(self_ip, rng),
             peers: vec![],
+            nodes: vec![],
+            nodes6: vec![],
+            seen: vec![],
+            keypair: Keypair::generate(rng).to_bytes().to_vec(),
+            ids: Default::default(),
+        }
+    }
+
+    /// Node id of the bound address, BEP 45.  A new id is generated if
+    /// the address has none, or the stored one doesn't match it.
+    ///
+    /// The id of a wildcard address is kept as is: the external address
+    /// is known only from the votes, which replace the id if it doesn't
+    /// match.
+    pub(crate) fn id_for<R: Rng + CryptoRng>(&mut self, ip: IpAddr, rng: &mut R) -> DhtId {
+        let key = ip.to_string();
+        let fits = |id: &DhtId| ip.is_unspecified() || crate::bep_0042::is_valid_id(id, ip);
+        match self.ids.get(&key) {
+            Some(id) if fits(id) => id.clone(),
+            _ => {
+                // Old state files have the id of a single address only.
+                let id = if self.ids.is_empty() && fits(&self.dht_id) {
+                    self.dht_id.clone()
+                } else {
+                    crate::bep_0042::gen_self_id(ip, rng)
+                };
+                self.ids.insert(key, id.clone());
+                id
+            }
+        }
+    }
+
+    /// Record the new id of the bound address.
+    pub(crate) fn set_id(&mut self, ip: IpAddr, id: DhtId) {
+        self.ids.insert(ip.to_string(), id);
+    }
+
+    /// Saved contacts of the routing tables, with the times they were
+    /// last seen.  Malformed lists are ignored.
+    pub(crate) fn contacts(&self) -> Vec<(Contact, SystemTime)> {
+        let mut contacts = vec![];
+        if self.nodes.len() % COMPACT_NODE_BYTE_SIZE == 0 {
+            let nodes = CompactNodesList(Cow::Borrowed(&self.nodes));
+            contacts.extend(
+                nodes
+                    .iter()
+                    .map(|n| Contact::new(n.id.clone(), n.socket_addr())),
+            );
+        }
+        if self.nodes6.len() % COMPACT_NODE6_BYTE_SIZE == 0 {
+            let nodes6 = CompactNodes6List(Cow::Borrowed(&self.nodes6));
+            contacts.extend(
+                nodes6
+                    .iter()
+                    .map(|n| Contact::new(n.id.clone(), n.socket_addr())),
+            );
+        }
+        // The times don't match the contacts if a list is malformed.
+        let seen: Vec<SystemTime> = if self.seen.len() == contacts.len() {
+            self.seen
+                .iter()
+                .map(|secs| UNIX_EPOCH + Duration::from_secs(*secs))
+                .collect()
+        } else {
+            vec![UNIX_EPOCH; contacts.len()]
+        };
+        contacts.into_iter().zip(seen).collect()
+    }
+
+    pub(crate) fn set_contacts(&mut self, contacts: &[(Contact, SystemTime)]) {
+        let (v4, v6): (Vec<_>, Vec<_>) = contacts
+            .iter()
+            .partition(|(contact, _)| contact.addr.is_ipv4());
+        self.nodes = CompactNodesList::from_contacts(v4.iter().map(|(contact, _)| contact))
+            .0
+            .into_owned();
+        self.nodes6 = CompactNodes6List::from_contacts(v6.iter().map(|(contact, _)| contact))
+            .0
+            .into_owned();
+        self.seen = v4
+            .iter()
+            .chain(&v6)
+            .map(|(_, seen)| {
+                seen.duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs()
+            })
+            .collect();
+    }
+
+    pub(crate) fn load<P: AsRef<Path>>(filename: P) -> Result<Config, StateError> {
+        let config_data = fs::read(filename)?;
+        Self::decode(&config_data)
+    }
+
+    fn decode(config_data: &[u8]) -> Result<Config, StateError> {
+        let mut config =
+            serde_bencoded::from_bytes_auto::<Config>(config_data).map_err(StateError::Decode)?;
+        if config.version > STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(config.version));
+        }
+        config.migrate();
+        if Keypair::from_bytes(&config.keypair).is_err() {
+            config.keypair = Keypair::generate(&mut OsRng).to_bytes().to_vec();
         }
+        Ok(config)
     }
 
-    pub(crate) fn load(filename: &str) -> Result<Config, serde_bencoded::DeError> {
-        let mut file = File::open(filename).unwrap();
-        let mut config_data = vec![];
-        file.read_to_end(&mut config_data).unwrap();
-        serde_bencoded::from_bytes_auto::<Config>(&config_data)
+    /// Bring a config of an older format version up to date.
+    fn migrate(&mut self) {
+        // Version 0 is the same, but `peers` was a stub.  A list put
+        // there by hand is kept.
+        self.version = STATE_VERSION;
     }
 
-    pub(crate) fn write(&self, filename: &str) -> Result<(), serde_bencoded::SerError> {
-        let config_data = serde_bencoded::to_vec(self)?;
-        let mut file = File::create(filename).unwrap();
-        file.write(&config_data).unwrap();
+    pub(crate) fn keypair(&self) -> Keypair {
+        // The keypair is validated on load.
+        Keypair::from_bytes(&self.keypair).expect("invalid keypair")
+    }
+
+    /// Write the config atomically: a crash leaves either the old file
+    /// or the new one.
+    pub(crate) fn write<P: AsRef<Path>>(&self, filename: P) -> Result<(), StateError> {
+        let filename = filename.as_ref();
+        let config_data = serde_bencoded::to_vec(self).map_err(StateError::Encode)?;
+        let tmp = tmp_path(filename);
+        let mut file = create_private(&tmp)?;
+        let written = file
+            .write_all(&config_data)
+            .and_then(|_| file.sync_all())
+            .and_then(|_| fs::rename(&tmp, filename));
+        if let Err(e) = written {
+            let _ = fs::remove_file(&tmp);
+            return Err(e.into());
+        }
+        // Make the rename durable too.
+        #[cfg(unix)]
+        {
+            let dir = match filename.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir,
+                _ => Path::new("."),
+            };
+            File::open(dir)?.sync_all()?;
+        }
         Ok(())
     }
 }
 
+/// Create a new file only the owner can read: the state has the
+/// secret key.
+fn create_private(path: &Path) -> io::Result<File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+/// Temporary file the state is written to before it replaces `path`.
+/// It is in the same directory for the rename, with a random name so
+/// that concurrent writers don't share it.
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{:016x}.tmp", OsRng.next_u64()));
+    tmp.into()
+}
+
 pub(crate) fn init_chacha() -> ChaCha20Rng {
     let mut random: <ChaCha20Rng as SeedableRng>::Seed = Default::default();
     OsRng.fill_bytes(&mut random);
@@ -401,6 +1170,158 @@ mod tests {
     use std::error::Error;
 
     #[test]
+    fn test_parse_id() {
+        let id: DhtId = "5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401".parse().unwrap();
+        assert_eq!(id.to_string(), "5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401");
+        assert_eq!("5fbf".parse::<DhtId>(), Err(ParseIdError::Length));
+        assert_eq!(
+            "+fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401".parse::<DhtId>(),
+            Err(ParseIdError::Hex)
+        );
+        assert_eq!(
+            "5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee4é".parse::<DhtId>(),
+            Err(ParseIdError::Hex)
+        );
+    }
+
+    #[test]
+    fn test_config_keypair() -> Result<(), Box<dyn Error>> {
+        let mut rng = init_chacha();
+        let config = Config::new(&mut rng, [124, 31, 75, 21].into());
+        let data = serde_bencoded::to_vec(&config)?;
+        let loaded: Config = serde_bencoded::from_bytes_auto(&data)?;
+        assert_eq!(loaded.dht_id, config.dht_id);
+        assert_eq!(
+            loaded.keypair().public.to_bytes(),
+            config.keypair().public.to_bytes()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_ids() -> Result<(), Box<dyn Error>> {
+        let mut rng = init_chacha();
+        let ip: IpAddr = [124, 31, 75, 21].into();
+        let other: IpAddr = [21, 75, 31, 124].into();
+        let mut config = Config::new(&mut rng, ip);
+
+        // The old single id is kept for its address.
+        assert_eq!(config.id_for(ip, &mut rng), config.dht_id);
+        let other_id = config.id_for(other, &mut rng);
+        assert_ne!(other_id, config.dht_id);
+        assert!(crate::bep_0042::is_valid_id(&other_id, other));
+
+        let data = serde_bencoded::to_vec(&config)?;
+        let mut loaded: Config = serde_bencoded::from_bytes_auto(&data)?;
+        assert_eq!(loaded.id_for(other, &mut rng), other_id);
+
+        // An id not matching the address is replaced.
+        loaded.set_id(other, config.dht_id.clone());
+        let new_id = loaded.id_for(other, &mut rng);
+        assert_ne!(new_id, config.dht_id);
+        assert!(crate::bep_0042::is_valid_id(&new_id, other));
+
+        // The voted id of a wildcard address is kept.
+        let any: IpAddr = Ipv4Addr::UNSPECIFIED.into();
+        loaded.set_id(any, config.dht_id.clone());
+        assert_eq!(loaded.id_for(any, &mut rng), config.dht_id);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_contacts() -> Result<(), Box<dyn Error>> {
+        let mut rng = init_chacha();
+        let mut config = Config::new(&mut rng, [124, 31, 75, 21].into());
+        assert!(config.contacts().is_empty());
+        assert!(config.peers.is_empty());
+
+        let seen = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let contacts = vec![
+            (
+                Contact::new(
+                    DhtId(*b"mnopqrstuvwxyz123456"),
+                    "[2001:db8::1]:6881".parse()?,
+                ),
+                seen,
+            ),
+            (
+                Contact::new(DhtId(*b"abcdefghij0123456789"), "192.0.2.1:6881".parse()?),
+                seen + Duration::from_secs(60),
+            ),
+        ];
+        config.set_contacts(&contacts);
+        let data = serde_bencoded::to_vec(&config)?;
+        let mut loaded: Config = serde_bencoded::from_bytes_auto(&data)?;
+        // IPv4 contacts come first.
+        assert_eq!(
+            loaded.contacts(),
+            vec![contacts[1].clone(), contacts[0].clone()]
+        );
+
+        loaded.nodes.pop();
+        assert_eq!(loaded.contacts(), vec![(contacts[0].0.clone(), UNIX_EPOCH)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_file() -> Result<(), Box<dyn Error>> {
+        let path = std::env::temp_dir().join(format!("duhast-config-{}.state", std::process::id()));
+        let config = Config::new(&mut init_chacha(), [124, 31, 75, 21].into());
+        config.write(&path)?;
+        let tmp_prefix = format!("{}.", path.file_name().unwrap().to_string_lossy());
+        for entry in fs::read_dir(path.parent().unwrap())? {
+            assert!(!entry?
+                .file_name()
+                .to_string_lossy()
+                .starts_with(&tmp_prefix));
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path)?.permissions().mode() & 0o777, 0o600);
+        }
+        let loaded = Config::load(&path)?;
+        assert_eq!(loaded.version, STATE_VERSION);
+        assert_eq!(loaded.dht_id, config.dht_id);
+
+        fs::write(&path, b"d6:dht_id20:abcdefghij0123456789")?;
+        assert!(matches!(Config::load(&path), Err(StateError::Decode(_))));
+        fs::remove_file(&path)?;
+        match Config::load(&path) {
+            Err(StateError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::NotFound),
+            _ => panic!("expecting I/O error"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_migrate() -> Result<(), Box<dyn Error>> {
+        // Version 0 has no version key and a stub peers list.
+        let config = Config::decode(b"d6:dht_id20:abcdefghij01234567895:peerslee")?;
+        assert_eq!(config.version, STATE_VERSION);
+        assert_eq!(config.dht_id, DhtId(*b"abcdefghij0123456789"));
+        assert!(config.peers.is_empty());
+        assert!(Keypair::from_bytes(&config.keypair).is_ok());
+
+        let data = serde_bencoded::to_vec(&config)?;
+        let mut loaded = Config::decode(&data)?;
+        assert_eq!(loaded.peers, config.peers);
+
+        // A version 0 list put there by hand is kept.
+        let config = Config::decode(b"d6:dht_id20:abcdefghij01234567895:peersl9:host:6881ee")?;
+        assert_eq!(config.peers, vec!["host:6881".to_owned()]);
+
+        loaded.version = STATE_VERSION + 1;
+        let data = serde_bencoded::to_vec(&loaded)?;
+        assert!(matches!(
+            Config::decode(&data),
+            Err(StateError::UnsupportedVersion(v)) if v == STATE_VERSION + 1
+        ));
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_unpack_incoming_msg() -> Result<(), Box<dyn Error>> {
         const DATA: &[u8] = b"d1:ad2:id20:\xFFbcdefghij0123456789e1:q4:ping1:y1:q1:t2:\xFF\xFFe";
         let ping: IncomingMessage = serde_bencoded::from_bytes_auto(&DATA)?;
@@ -411,12 +1332,14 @@ mod tests {
                 y: "q",
                 t: b"\xFF\xFF",
                 ro: None,
+                ip: None,
             }
         );
         Ok(())
     }
 
     #[test]
+    #[allow(clippy::needless_borrow)]
     fn test_unpack_incoming_msg_ro() -> Result<(), Box<dyn Error>> {
         const DATA: &[u8] =
             b"d1:ad2:id20:\xFFbcdefghij0123456789e1:q4:ping2:roi1e1:y1:q1:t2:\xFF\xFFe";
@@ -428,12 +1351,44 @@ mod tests {
                 y: "q",
                 t: b"\xFF\xFF",
                 ro: Some(true),
+                ip: None,
             }
         );
         Ok(())
     }
 
     #[test]
+    fn test_unpack_incoming_msg_ip() -> Result<(), Box<dyn Error>> {
+        const DATA: &[u8] =
+            b"d2:ip6:\x7c\x1f\x4b\x15\x1a\xe11:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re";
+        let msg: IncomingMessage = serde_bencoded::from_bytes_auto(DATA)?;
+
+        assert_eq!(
+            msg.ip.map(|ip| ip.socket_addr()),
+            Some(SocketAddr::from(([124, 31, 75, 21], 6881)))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_pack_read_only_query() -> Result<(), Box<dyn Error>> {
+        let msg = OutgoingMessage {
+            t: Cow::Borrowed(b"aa"),
+            ip: None,
+            ro: Some(true),
+            msg: Message::<()>::Q(Query::Ping(PingQuery {
+                id: DhtId(*b"abcdefghij0123456789"),
+            })),
+        };
+        assert_eq!(
+            serde_bencoded::to_vec(&msg)?,
+            b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping2:roi1e1:t2:aa1:y1:qe"
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::clone_on_copy, clippy::needless_borrow)]
     fn test_unpack_ping_query() -> Result<(), Box<dyn Error>> {
         const DATA: &[u8] =
             b"d1:ad2:id20:\xFFbcdefghij0123456789e1:q4:ping1:t2:aa1:y1:q1:t2:\xFF\xFFe";
@@ -449,6 +1404,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::clone_on_copy, clippy::needless_borrow)]
     fn test_unpack_find_node_query() -> Result<(), Box<dyn Error>> {
         const DATA: &[u8] = b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q9:find_node1:t2:aa1:y1:qe";
         let find_node: Message<()> = serde_bencoded::from_bytes_auto(&DATA)?;
@@ -458,12 +1414,30 @@ mod tests {
             Message::Q(Query::FindNode(FindNodeQuery {
                 id: DhtId(b"abcdefghij0123456789".clone()),
                 target: DhtId(b"mnopqrstuvwxyz123456".clone()),
+                want: None,
+            }))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unpack_find_node_query_want() -> Result<(), Box<dyn Error>> {
+        const DATA: &[u8] = b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz1234564:wantl2:n42:n6ee1:q9:find_node1:t2:aa1:y1:qe";
+        let find_node: Message<()> = serde_bencoded::from_bytes_auto(DATA)?;
+
+        assert_eq!(
+            find_node,
+            Message::Q(Query::FindNode(FindNodeQuery {
+                id: DhtId(*b"abcdefghij0123456789"),
+                target: DhtId(*b"mnopqrstuvwxyz123456"),
+                want: Some(vec![WANT_V4.to_owned(), WANT_V6.to_owned()]),
             }))
         );
         Ok(())
     }
 
     #[test]
+    #[allow(clippy::clone_on_copy, clippy::needless_borrow)]
     fn test_unpack_get_peers_query() -> Result<(), Box<dyn Error>> {
         const DATA: &[u8] = b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456e1:q9:get_peers1:t2:aa1:y1:qe";
         let get_peers: Message<()> = serde_bencoded::from_bytes_auto(&DATA)?;
@@ -472,12 +1446,48 @@ mod tests {
             Message::Q(Query::GetPeers(GetPeersQuery {
                 id: DhtId(b"abcdefghij0123456789".clone()),
                 info_hash: DhtId(b"mnopqrstuvwxyz123456".clone()),
+                want: None,
+                noseed: None,
+                scrape: None,
             }))
         );
         Ok(())
     }
 
     #[test]
+    fn test_unpack_scrape() -> Result<(), Box<dyn Error>> {
+        const QUERY: &[u8] = b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz1234566:noseedi0e6:scrapei1ee1:q9:get_peers1:t2:aa1:y1:qe";
+        let query: Message<()> = serde_bencoded::from_bytes_auto(QUERY)?;
+        assert_eq!(
+            query,
+            Message::Q(Query::GetPeers(GetPeersQuery {
+                id: DhtId(*b"abcdefghij0123456789"),
+                info_hash: DhtId(*b"mnopqrstuvwxyz123456"),
+                want: None,
+                noseed: Some(0),
+                scrape: Some(1),
+            }))
+        );
+
+        let mut response = b"d1:rd4:BFpe256:".to_vec();
+        response.extend_from_slice(&[0; 256]);
+        response.extend_from_slice(b"4:BFsd256:");
+        response.extend_from_slice(&[0xff; 256]);
+        response.extend_from_slice(b"2:id20:abcdefghij01234567895:token8:aoeusnthe1:t2:aa1:y1:re");
+        let response: Message<GetPeersResponse> = serde_bencoded::from_bytes_auto(&response)?;
+        match response {
+            Message::R { r } => {
+                assert_eq!(r.bf_pe, Some(BloomFilter::default()));
+                assert!(r.bf_sd.unwrap().estimate() > 6000.0);
+                assert_eq!(r.values, None);
+            }
+            _ => panic!("unexpected message {:?}", response),
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::clone_on_copy, clippy::needless_borrow)]
     fn test_unpack_announce_peer_query() -> Result<(), Box<dyn Error>> {
         const DATA: &[u8] = b"d1:ad2:id20:abcdefghij012345678912:implied_porti1e9:info_hash20:mnopqrstuvwxyz1234564:porti6881e5:token8:aoeusnthe1:q13:announce_peer1:t2:aa1:y1:qe";
         let announce_peer: Message<()> = serde_bencoded::from_bytes_auto(&DATA)?;
@@ -488,13 +1498,82 @@ mod tests {
                 implied_port: 1,
                 info_hash: DhtId(b"mnopqrstuvwxyz123456".clone()),
                 port: 6881,
-                token: Cow::Borrowed(b"aoeusnth")
+                token: Cow::Borrowed(b"aoeusnth"),
+                seed: None,
             }))
         );
         Ok(())
     }
 
     #[test]
+    fn test_unpack_put_query() -> Result<(), Box<dyn Error>> {
+        const DATA: &[u8] = b"d1:ad2:id20:abcdefghij01234567895:token8:aoeusnth1:v12:Hello World!e1:q3:put1:t2:aa1:y1:qe";
+        let put: Message<()> = serde_bencoded::from_bytes_auto(DATA)?;
+        assert_eq!(
+            put,
+            Message::Q(Query::Put(PutQuery::immutable(
+                DhtId(*b"abcdefghij0123456789"),
+                Cow::Borrowed(b"aoeusnth"),
+                Value::Bytes(b"Hello World!".to_vec()),
+            )))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unpack_mutable_put_query() -> Result<(), Box<dyn Error>> {
+        const DATA: &[u8] = b"d1:ad3:casi1e2:id20:abcdefghij01234567891:k32:0123456789abcdef0123456789abcdef4:salt6:foobar3:seqi2e3:sig64:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef5:token8:aoeusnth1:vi42ee1:q3:put1:t2:aa1:y1:qe";
+        let put: Message<()> = serde_bencoded::from_bytes_auto(DATA)?;
+        match &put {
+            Message::Q(Query::Put(q)) => {
+                assert_eq!(
+                    q.k.as_deref(),
+                    Some(&b"0123456789abcdef0123456789abcdef"[..])
+                );
+                assert_eq!(q.sig.as_ref().map(|sig| sig.len()), Some(64));
+                assert_eq!(q.salt.as_deref(), Some(&b"foobar"[..]));
+                assert_eq!(q.seq, Some(2));
+                assert_eq!(q.cas, Some(1));
+                assert_eq!(q.v, Value::Int(42));
+            }
+            _ => panic!("unexpected message {:?}", put),
+        }
+
+        let packed = serde_bencoded::to_vec(&OutgoingMessage {
+            t: Cow::Borrowed(b"aa"),
+            ip: None,
+            ro: None,
+            msg: put,
+        })?;
+        assert_eq!(packed, DATA);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unpack_get_response() -> Result<(), Box<dyn Error>> {
+        const DATA: &[u8] =
+            b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth1:vli1ei2eee1:t2:aa1:y1:re";
+        let get: Message<GetResponse> = serde_bencoded::from_bytes_auto(DATA)?;
+        assert_eq!(
+            get,
+            Message::R {
+                r: GetResponse {
+                    id: DhtId(*b"abcdefghij0123456789"),
+                    token: Cow::Borrowed(b"aoeusnth"),
+                    v: Some(Value::List(vec![Value::Int(1), Value::Int(2)])),
+                    k: None,
+                    sig: None,
+                    seq: None,
+                    nodes: None,
+                    nodes6: None,
+                }
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::clone_on_copy, clippy::needless_borrow)]
     fn test_unpack_ping_response() -> Result<(), Box<dyn Error>> {
         const DATA: &[u8] = b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re";
         let ping: Message<PingResponse> = serde_bencoded::from_bytes_auto(&DATA)?;
@@ -510,6 +1589,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::clone_on_copy, clippy::needless_borrow)]
     fn test_unpack_find_node_response() -> Result<(), Box<dyn Error>> {
         const DATA: &[u8] =
             b"d1:rd2:id20:0123456789abcdefghij5:nodes26:01234567890123456789abcdefe1:t2:aa1:y1:re";
@@ -519,9 +1599,10 @@ mod tests {
             Message::R {
                 r: FindNodeResponse {
                     id: DhtId(b"0123456789abcdefghij".clone()),
-                    nodes: CompactNodesList(Cow::Owned(Vec::from(
+                    nodes: Some(CompactNodesList(Cow::Owned(Vec::from(
                         b"01234567890123456789abcdef".clone()
-                    )))
+                    )))),
+                    nodes6: None,
                 }
             }
         );
@@ -529,6 +1610,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::clone_on_copy)]
     fn test_unpack_get_peers_response_values() -> Result<(), Box<dyn Error>> {
         const DATA: &[u8] =
     b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth6:valuesl6:axje.u6:idhtnmee1:t2:aa1:y1:re";
@@ -540,10 +1622,13 @@ mod tests {
                     id: DhtId(b"abcdefghij0123456789".clone()),
                     token: Cow::Borrowed(b"aoeusnth"),
                     values: Some(vec![
-                        NodeAddr(b"axje.u".clone()),
-                        NodeAddr(b"idhtnm".clone())
+                        NodeAddr::V4(b"axje.u".clone()),
+                        NodeAddr::V4(b"idhtnm".clone())
                     ]),
                     nodes: None,
+                    nodes6: None,
+                    bf_sd: None,
+                    bf_pe: None,
                 }
             }
         );
@@ -551,6 +1636,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::clone_on_copy)]
     fn test_unpack_get_peers_response_nodes() -> Result<(), Box<dyn Error>> {
         const DATA: &[u8] =
     b"d1:rd2:id20:abcdefghij01234567895:token8:aoeusnth5:nodes26:01234567890123456789012345e1:t2:aa1:y1:re";
@@ -565,6 +1651,9 @@ mod tests {
                     nodes: Some(CompactNodesList(Cow::Owned(Vec::from(
                         b"01234567890123456789012345".clone()
                     )))),
+                    nodes6: None,
+                    bf_sd: None,
+                    bf_pe: None,
                 }
             }
         );
@@ -572,6 +1661,64 @@ mod tests {
     }
 
     #[test]
+    fn test_unpack_get_peers_response_ipv6() -> Result<(), Box<dyn Error>> {
+        const DATA: &[u8] = b"d1:rd2:id20:abcdefghij01234567896:nodes638:012345678901234567890123456789012345\x1a\xe15:token8:aoeusnth6:valuesl18:0123456789abcdef\x1a\xe1ee1:t2:aa1:y1:re";
+        let get_peers: Message<GetPeersResponse> = serde_bencoded::from_bytes_auto(DATA)?;
+        let r = match get_peers {
+            Message::R { r } => r,
+            _ => panic!("response expected"),
+        };
+
+        assert_eq!(r.nodes, None);
+        let nodes: Vec<_> = r.nodes6.as_ref().unwrap().iter().collect();
+        assert_eq!(nodes.len(), 1);
+        assert_eq!(nodes[0].id, DhtId(*b"01234567890123456789"));
+        assert_eq!(
+            nodes[0].socket_addr(),
+            SocketAddr::from((*b"0123456789012345", 6881))
+        );
+        assert_eq!(
+            r.values.unwrap()[0].socket_addr(),
+            SocketAddr::from((*b"0123456789abcdef", 6881))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unpack_sample_infohashes() -> Result<(), Box<dyn Error>> {
+        const QUERY: &[u8] = b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q17:sample_infohashes1:t2:aa1:y1:qe";
+        let query: Message<()> = serde_bencoded::from_bytes_auto(QUERY)?;
+        assert_eq!(
+            query,
+            Message::Q(Query::SampleInfohashes(SampleInfohashesQuery {
+                id: DhtId(*b"abcdefghij0123456789"),
+                target: DhtId(*b"mnopqrstuvwxyz123456"),
+            }))
+        );
+
+        const RESPONSE: &[u8] = b"d1:rd2:id20:abcdefghij01234567898:intervali21600e5:nodes26:012345678901234567890123453:numi2e7:samples40:0123456789abcdefghijABCDEFGHIJ0123456789e1:t2:aa1:y1:re";
+        let response: Message<SampleInfohashesResponse> =
+            serde_bencoded::from_bytes_auto(RESPONSE)?;
+        match response {
+            Message::R { r } => {
+                assert_eq!(r.interval, 21600);
+                assert_eq!(r.num, 2);
+                assert_eq!(
+                    r.samples.iter().collect::<Vec<_>>(),
+                    vec![
+                        DhtId(*b"0123456789abcdefghij"),
+                        DhtId(*b"ABCDEFGHIJ0123456789")
+                    ]
+                );
+                assert_eq!(r.nodes.unwrap().iter().count(), 1);
+            }
+            _ => panic!("unexpected message {:?}", response),
+        }
+        Ok(())
+    }
+
+    #[test]
+    #[allow(clippy::clone_on_copy)]
     fn test_unpack_announce_peer_response() -> Result<(), Box<dyn Error>> {
         const DATA: &[u8] = b"d1:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re";
         let announce_peers: Message<AnnouncePeerResponse> = serde_bencoded::from_bytes_auto(DATA)?;
@@ -587,6 +1734,7 @@ mod tests {
     }
 
     #[test]
+    #[rustfmt::skip]
     fn test_unpack_error_response() -> Result<(), Box<dyn Error>> {
         const DATA: &[u8] = b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee";
         let err: Message<PingResponse> = serde_bencoded::from_bytes_auto(DATA)?;
@@ -0,0 +1,109 @@
+//! Discovery of our external address from the `ip` field of
+//! responses, as described in
+//! https://www.bittorrent.org/beps/bep_0042.html
+//!
+//! Every responder votes for the address it sees us from; only the
+//! latest vote of each responder counts, so a single node cannot
+//! outvote the others.
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+
+/// Votes needed before an address is accepted.
+const MIN_VOTES: usize = 3;
+/// Only the votes of this number of latest voters are kept.
+const MAX_VOTERS: usize = 64;
+
+#[derive(Default)]
+pub(crate) struct ExternalIp {
+    /// Voter's address to the address it reported.
+    votes: HashMap<IpAddr, IpAddr>,
+    /// Voters, the oldest first.
+    voters: VecDeque<IpAddr>,
+    current: Option<IpAddr>,
+}
+
+impl ExternalIp {
+    pub(crate) fn current(&self) -> Option<IpAddr> {
+        self.current
+    }
+
+    /// Record a vote; returns the new external address if the vote
+    /// changes it.
+    pub(crate) fn vote(&mut self, voter: IpAddr, reported: IpAddr) -> Option<IpAddr> {
+        // A node of other address family cannot see our address.
+        if voter.is_ipv4() != reported.is_ipv4() {
+            return None;
+        }
+        if self.votes.insert(voter, reported).is_some() {
+            self.voters.retain(|v| *v != voter);
+        }
+        self.voters.push_back(voter);
+        if self.voters.len() > MAX_VOTERS {
+            if let Some(oldest) = self.voters.pop_front() {
+                self.votes.remove(&oldest);
+            }
+        }
+
+        let count = |ip: IpAddr| self.votes.values().filter(|v| **v == ip).count();
+        let votes = count(reported);
+        let current_votes = self.current.map_or(0, count);
+        if Some(reported) != self.current && votes >= MIN_VOTES && votes > current_votes {
+            self.current = Some(reported);
+            self.current
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(n: u8) -> IpAddr {
+        [1, 2, 3, n].into()
+    }
+
+    #[test]
+    fn test_distinct_voters() {
+        let mut external = ExternalIp::default();
+
+        // The same voter counts once.
+        for _ in 0..MIN_VOTES {
+            assert_eq!(external.vote(ip(1), ip(100)), None);
+        }
+        for n in 2..MIN_VOTES as u8 {
+            assert_eq!(external.vote(ip(n), ip(100)), None);
+        }
+        assert_eq!(external.vote(ip(MIN_VOTES as u8), ip(100)), Some(ip(100)));
+        assert_eq!(external.current(), Some(ip(100)));
+        assert_eq!(external.vote(ip(10), ip(100)), None);
+    }
+
+    #[test]
+    fn test_majority_change() {
+        let mut external = ExternalIp::default();
+        for n in 1..=MIN_VOTES as u8 {
+            external.vote(ip(n), ip(100));
+        }
+
+        // Voters change their minds one by one.
+        for n in 1..MIN_VOTES as u8 {
+            assert_eq!(external.vote(ip(n), ip(200)), None);
+        }
+        assert_eq!(external.vote(ip(MIN_VOTES as u8), ip(200)), Some(ip(200)));
+        assert_eq!(external.current(), Some(ip(200)));
+    }
+
+    #[test]
+    fn test_other_family_ignored() {
+        let mut external = ExternalIp::default();
+        for n in 1..=MIN_VOTES as u8 {
+            assert_eq!(
+                external.vote(ip(n), std::net::Ipv6Addr::LOCALHOST.into()),
+                None
+            );
+        }
+        assert_eq!(external.current(), None);
+    }
+}
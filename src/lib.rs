@@ -0,0 +1,46 @@
+//! BitTorrent mainline DHT node.
+//!
+//! [`DhtBuilder`] binds the node and [`Dht`] is a cloneable handle to
+//! the running node:
+//!
+//! ```no_run
+//! # async fn example() -> std::io::Result<()> {
+//! use du_has_t::{DhtBuilder, DhtId};
+//! use futures::StreamExt;
+//!
+//! let dht = DhtBuilder::new()
+//!     .with_bind_address("0.0.0.0:6881")
+//!     .with_default_bootstrap_nodes(vec!["router.bittorrent.com:6881"])
+//!     .build()
+//!     .await?;
+//! dht.bootstrap().await;
+//! let info_hash: DhtId = "4175ef7e2691d08aa4dc6b848e35df84e8fe175b".parse().unwrap();
+//! let mut peers = Box::pin(dht.get_peers(info_hash));
+//! while let Some(peer) = peers.next().await {
+//!     println!("{}", peer);
+//! }
+//! dht.shutdown().await
+//! # }
+//! ```
+mod bencode;
+mod bep_0033;
+mod bep_0042;
+mod bep_0044;
+mod bep_0046;
+mod dht;
+mod external_ip;
+mod lookup;
+mod node;
+mod peer_store;
+mod query_queue;
+mod routing_table;
+mod server;
+mod token;
+
+pub use crate::bep_0044::{ItemError, MutableItem, Value};
+pub use crate::bep_0046::{MagnetLink, ParseMagnetError};
+pub use crate::dht::{DhtId, ParseIdError};
+pub use crate::lookup::ScrapeEstimate;
+pub use crate::node::{Dht, DhtBuilder};
+pub use crate::query_queue::{KrpcError, RetryPolicy, RetryPolicyError};
+pub use crate::routing_table::Contact;
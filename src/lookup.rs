@@ -0,0 +1,1308 @@
+//! Iterative Kademlia lookup.
+use crate::bep_0033::BloomFilter;
+use crate::bep_0044::{self, Item, ItemError, MutableItem, Value};
+use crate::bep_0046::{self, MagnetLink};
+use crate::dht;
+use crate::dht::DhtId;
+use crate::query_queue::{KrpcError, QueryQueue, RetryPolicy};
+use crate::routing_table::{Contact, InsertOutcome, RoutingTable, K};
+use ed25519_dalek::Keypair;
+use futures::future;
+use futures::stream::{self, FuturesUnordered, Stream, StreamExt};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::Mutex as StdMutex;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+/// Default number of concurrent queries.
+pub(crate) const ALPHA: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    NotQueried,
+    InFlight,
+    Responded,
+    Failed,
+}
+
+struct Candidate {
+    contact: Contact,
+    distance: DhtId,
+    state: State,
+}
+
+/// Shortlist of candidates sorted by distance to the target.
+struct Shortlist {
+    target: DhtId,
+    candidates: Vec<Candidate>,
+    seen: HashSet<DhtId>,
+}
+
+impl Shortlist {
+    fn new(target: DhtId) -> Self {
+        Self {
+            target,
+            candidates: vec![],
+            seen: Default::default(),
+        }
+    }
+
+    fn add(&mut self, contact: Contact) {
+        if self.seen.insert(contact.id.clone()) {
+            let distance = contact.id.distance(&self.target);
+            let pos = self
+                .candidates
+                .binary_search_by(|cand| cand.distance.cmp(&distance))
+                .unwrap_or_else(|pos| pos);
+            self.candidates.insert(
+                pos,
+                Candidate {
+                    contact,
+                    distance,
+                    state: State::NotQueried,
+                },
+            );
+        }
+    }
+
+    fn set_state(&mut self, id: &DhtId, state: State) {
+        if let Some(cand) = self
+            .candidates
+            .iter_mut()
+            .find(|cand| &cand.contact.id == id)
+        {
+            cand.state = state;
+        }
+    }
+
+    /// The `k` closest candidates that haven't failed.
+    fn closest_alive(&mut self, k: usize) -> impl Iterator<Item = &mut Candidate> {
+        self.candidates
+            .iter_mut()
+            .filter(|cand| cand.state != State::Failed)
+            .take(k)
+    }
+
+    fn responded(&self, k: usize) -> Vec<Contact> {
+        self.candidates
+            .iter()
+            .filter(|cand| cand.state == State::Responded)
+            .take(k)
+            .map(|cand| cand.contact.clone())
+            .collect()
+    }
+}
+
+/// Generic lookup driver.  It queries up to `alpha` candidates
+/// concurrently, always choosing the closest not yet queried ones,
+/// until the `k` closest candidates have all responded.  `query`
+/// returns nodes the queried node knows.
+///
+/// Returns up to `k` closest contacts that have responded.
+pub(crate) async fn lookup<F, Fut, E>(
+    target: DhtId,
+    seeds: Vec<Contact>,
+    alpha: usize,
+    k: usize,
+    mut query: F,
+) -> Vec<Contact>
+where
+    F: FnMut(Contact) -> Fut,
+    Fut: Future<Output = Result<Vec<Contact>, E>>,
+{
+    let mut shortlist = Shortlist::new(target);
+    for contact in seeds {
+        shortlist.add(contact);
+    }
+
+    let mut in_flight = FuturesUnordered::new();
+    loop {
+        let to_query: Vec<Contact> = shortlist
+            .closest_alive(k)
+            .filter(|cand| cand.state == State::NotQueried)
+            .take(alpha.saturating_sub(in_flight.len()))
+            .map(|cand| {
+                cand.state = State::InFlight;
+                cand.contact.clone()
+            })
+            .collect();
+        for contact in to_query {
+            let fut = query(contact.clone());
+            in_flight.push(async move { (contact, fut.await) });
+        }
+
+        match in_flight.next().await {
+            Some((contact, Ok(nodes))) => {
+                shortlist.set_state(&contact.id, State::Responded);
+                for node in nodes {
+                    shortlist.add(node);
+                }
+            }
+            Some((contact, Err(_))) => {
+                shortlist.set_state(&contact.id, State::Failed);
+            }
+            // Nothing is in flight and nothing to query: the k closest
+            // have responded, or all candidates have failed.
+            None => break,
+        }
+    }
+
+    shortlist.responded(k)
+}
+
+/// Nodes from `nodes` and `nodes6` of the same address family as
+/// the responder.  Each routing table holds single family only.
+fn reply_contacts(
+    nodes: &Option<dht::CompactNodesList>,
+    nodes6: &Option<dht::CompactNodes6List>,
+    self_id: &DhtId,
+    addr: &SocketAddr,
+) -> Vec<Contact> {
+    let v4 = nodes.iter().flat_map(|nodes| nodes.iter());
+    let v6 = nodes6.iter().flat_map(|nodes| nodes.iter());
+    v4.chain(v6)
+        .filter(|node| &node.id != self_id && node.ip.is_ipv4() == addr.is_ipv4())
+        .map(|node| Contact::new(node.id.clone(), node.socket_addr()))
+        .collect()
+}
+
+/// Record the reply of the node in the routing table.
+fn record_reply(
+    queue: &Arc<QueryQueue>,
+    udp: &Arc<UdpSocket>,
+    table: &Arc<StdMutex<RoutingTable>>,
+    contact: Contact,
+) {
+    let now = Instant::now();
+    let outcome = table.lock().unwrap().node_replied(contact.clone(), now);
+    if let InsertOutcome::PingQuestionable(questionable) = outcome {
+        replace_if_dead(
+            queue.clone(),
+            udp.clone(),
+            table.clone(),
+            questionable,
+            contact,
+            now,
+            RoutingTable::node_replied,
+        );
+    }
+}
+
+/// The bucket of the contact is full, BEP 5: ping its questionable
+/// node in the background, and put the contact in its place with
+/// `record` if the node doesn't respond.
+pub(crate) fn replace_if_dead(
+    queue: Arc<QueryQueue>,
+    udp: Arc<UdpSocket>,
+    table: Arc<StdMutex<RoutingTable>>,
+    questionable: Contact,
+    contact: Contact,
+    seen: Instant,
+    record: fn(&mut RoutingTable, Contact, Instant) -> InsertOutcome,
+) {
+    tokio::task::spawn(async move {
+        let retry = queue.retry_policy();
+        let res = ping_query(queue, udp, table.clone(), questionable.addr, retry).await;
+        if res.is_err() {
+            let mut table = table.lock().unwrap();
+            table.remove(&questionable.id);
+            record(&mut table, contact, seen);
+        }
+    });
+}
+
+/// Send single `ping` query with the retry policy, recording the
+/// responder in the routing table.  Returns the responder's id.
+pub(crate) async fn ping_query(
+    queue: Arc<QueryQueue>,
+    udp: Arc<UdpSocket>,
+    table: Arc<StdMutex<RoutingTable>>,
+    addr: SocketAddr,
+    retry: RetryPolicy,
+) -> Result<DhtId, KrpcError> {
+    let self_id = table.lock().unwrap().self_id().clone();
+    let query = dht::Query::Ping(dht::PingQuery { id: self_id });
+    let r: dht::PingResponse = queue
+        .clone()
+        .query_with(udp.clone(), addr, query, retry)
+        .await?;
+    record_reply(&queue, &udp, &table, Contact::new(r.id.clone(), addr));
+    Ok(r.id)
+}
+
+/// Send single `find_node` query, recording the responder in the
+/// routing table.
+pub(crate) async fn find_node_query(
+    queue: Arc<QueryQueue>,
+    udp: Arc<UdpSocket>,
+    table: Arc<StdMutex<RoutingTable>>,
+    addr: SocketAddr,
+    target: DhtId,
+) -> Result<Vec<Contact>, KrpcError> {
+    let self_id = table.lock().unwrap().self_id().clone();
+    let query = dht::Query::FindNode(dht::FindNodeQuery {
+        id: self_id.clone(),
+        target,
+        want: None,
+    });
+    let r: dht::FindNodeResponse = queue.clone().query(udp.clone(), addr, query).await?;
+    record_reply(&queue, &udp, &table, Contact::new(r.id, addr));
+    Ok(reply_contacts(&r.nodes, &r.nodes6, &self_id, &addr))
+}
+
+/// Find nodes closest to the target, starting from the closest nodes
+/// in the routing table.
+pub(crate) async fn find_node(
+    queue: Arc<QueryQueue>,
+    udp: Arc<UdpSocket>,
+    table: Arc<StdMutex<RoutingTable>>,
+    target: DhtId,
+) -> Vec<Contact> {
+    let seeds = table.lock().unwrap().closest(&target, K, Instant::now());
+    lookup(target.clone(), seeds, ALPHA, K, |contact| {
+        let queue = queue.clone();
+        let udp = udp.clone();
+        let table = table.clone();
+        let target = target.clone();
+        async move {
+            let res = find_node_query(queue, udp, table.clone(), contact.addr, target).await;
+            if res.is_err() {
+                table.lock().unwrap().query_failed(&contact.id);
+            }
+            res
+        }
+    })
+    .await
+}
+
+/// Reply to a single `get_peers` query.  `bf_sd` and `bf_pe` are
+/// present in replies to scrapes.
+pub(crate) struct GetPeersReply {
+    pub(crate) token: Vec<u8>,
+    pub(crate) values: Vec<SocketAddr>,
+    pub(crate) nodes: Vec<Contact>,
+    pub(crate) bf_sd: Option<BloomFilter>,
+    pub(crate) bf_pe: Option<BloomFilter>,
+}
+
+/// Send single `get_peers` query, recording the responder in the
+/// routing table.  `noseed` and `scrape` are BEP 33 flags.
+pub(crate) async fn get_peers_query(
+    queue: Arc<QueryQueue>,
+    udp: Arc<UdpSocket>,
+    table: Arc<StdMutex<RoutingTable>>,
+    addr: SocketAddr,
+    info_hash: DhtId,
+    noseed: bool,
+    scrape: bool,
+) -> Result<GetPeersReply, KrpcError> {
+    let self_id = table.lock().unwrap().self_id().clone();
+    let flag = |set: bool| if set { Some(1) } else { None };
+    let query = dht::Query::GetPeers(dht::GetPeersQuery {
+        id: self_id.clone(),
+        info_hash,
+        want: None,
+        noseed: flag(noseed),
+        scrape: flag(scrape),
+    });
+    let r: dht::GetPeersResponse = queue.clone().query(udp.clone(), addr, query).await?;
+    record_reply(&queue, &udp, &table, Contact::new(r.id, addr));
+    Ok(GetPeersReply {
+        token: r.token.into_owned(),
+        values: r
+            .values
+            .iter()
+            .flatten()
+            .map(dht::NodeAddr::socket_addr)
+            .collect(),
+        nodes: reply_contacts(&r.nodes, &r.nodes6, &self_id, &addr),
+        bf_sd: r.bf_sd,
+        bf_pe: r.bf_pe,
+    })
+}
+
+/// Iterative `get_peers` lookup.  Peers are sent to the channel as
+/// they arrive, possibly with duplicates.  With `noseed`, seeds are
+/// not requested.
+///
+/// Returns the closest nodes that have responded, with tokens they
+/// gave us.
+pub(crate) async fn get_peers_lookup(
+    queue: Arc<QueryQueue>,
+    udp: Arc<UdpSocket>,
+    table: Arc<StdMutex<RoutingTable>>,
+    info_hash: DhtId,
+    noseed: bool,
+    peers: mpsc::UnboundedSender<SocketAddr>,
+) -> Vec<(Contact, Vec<u8>)> {
+    let tokens = Arc::new(StdMutex::new(HashMap::<DhtId, Vec<u8>>::new()));
+    let seeds = table.lock().unwrap().closest(&info_hash, K, Instant::now());
+    let closest = lookup(info_hash.clone(), seeds, ALPHA, K, |contact| {
+        let queue = queue.clone();
+        let udp = udp.clone();
+        let table = table.clone();
+        let info_hash = info_hash.clone();
+        let tokens = tokens.clone();
+        let peers = peers.clone();
+        async move {
+            let reply = get_peers_query(
+                queue,
+                udp,
+                table.clone(),
+                contact.addr,
+                info_hash,
+                noseed,
+                false,
+            );
+            match reply.await {
+                Ok(reply) => {
+                    for peer in reply.values {
+                        // The receiver may be gone; the lookup is still
+                        // useful for its tokens.
+                        let _ = peers.send(peer);
+                    }
+                    tokens.lock().unwrap().insert(contact.id, reply.token);
+                    Ok(reply.nodes)
+                }
+                Err(e) => {
+                    table.lock().unwrap().query_failed(&contact.id);
+                    Err(e)
+                }
+            }
+        }
+    })
+    .await;
+
+    let mut tokens = tokens.lock().unwrap();
+    closest
+        .into_iter()
+        .filter_map(|contact| {
+            let token = tokens.remove(&contact.id)?;
+            Some((contact, token))
+        })
+        .collect()
+}
+
+/// Stream of unique peers of the torrent.  The lookup runs in a
+/// separate task, and the stream ends when the lookup is finished.
+pub(crate) fn get_peers(
+    queue: Arc<QueryQueue>,
+    udp: Arc<UdpSocket>,
+    table: Arc<StdMutex<RoutingTable>>,
+    info_hash: DhtId,
+    noseed: bool,
+) -> impl Stream<Item = SocketAddr> {
+    let (send, recv) = mpsc::unbounded_channel();
+    tokio::task::spawn(get_peers_lookup(queue, udp, table, info_hash, noseed, send));
+
+    stream::unfold((recv, HashSet::new()), |(mut recv, mut seen)| async move {
+        while let Some(peer) = recv.recv().await {
+            if seen.insert(peer) {
+                return Some((peer, (recv, seen)));
+            }
+        }
+        None
+    })
+}
+
+/// Send single `announce_peer` query.
+pub(crate) async fn announce_peer_query(
+    queue: Arc<QueryQueue>,
+    udp: Arc<UdpSocket>,
+    addr: SocketAddr,
+    query: dht::AnnouncePeerQuery<'static>,
+) -> Result<(), KrpcError> {
+    let query = dht::Query::AnnouncePeer(query);
+    queue
+        .query::<dht::AnnouncePeerResponse>(udp, addr, query)
+        .await?;
+    Ok(())
+}
+
+/// Announce us as a peer of the torrent to the closest nodes, using
+/// tokens they gave us during `get_peers` lookup.  With `implied_port`,
+/// the nodes use the source port of our packets instead of `port`.
+/// `seed` tells we have the complete torrent, BEP 33.
+///
+/// Returns number of nodes that have acknowledged the announce.
+pub(crate) async fn announce(
+    queue: Arc<QueryQueue>,
+    udp: Arc<UdpSocket>,
+    table: Arc<StdMutex<RoutingTable>>,
+    info_hash: DhtId,
+    port: u16,
+    implied_port: bool,
+    seed: bool,
+) -> usize {
+    let self_id = table.lock().unwrap().self_id().clone();
+    // Peers found are not interesting here.
+    let (send, _) = mpsc::unbounded_channel();
+    let closest = get_peers_lookup(
+        queue.clone(),
+        udp.clone(),
+        table.clone(),
+        info_hash.clone(),
+        true,
+        send,
+    )
+    .await;
+
+    let acks = future::join_all(closest.into_iter().map(|(contact, token)| {
+        announce_peer_query(
+            queue.clone(),
+            udp.clone(),
+            contact.addr,
+            dht::AnnouncePeerQuery {
+                id: self_id.clone(),
+                info_hash: info_hash.clone(),
+                token: Cow::Owned(token),
+                port,
+                implied_port: implied_port as u8,
+                seed: if seed { Some(1) } else { None },
+            },
+        )
+    }))
+    .await;
+    acks.into_iter().filter(Result::is_ok).count()
+}
+
+/// Swarm size estimated by a scrape, BEP 33.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScrapeEstimate {
+    pub seeds: f64,
+    pub leechers: f64,
+}
+
+/// Seed and downloader bloom filters of the closest nodes that have
+/// responded, merged.
+async fn scrape_filters(
+    queue: Arc<QueryQueue>,
+    udp: Arc<UdpSocket>,
+    table: Arc<StdMutex<RoutingTable>>,
+    info_hash: DhtId,
+) -> (BloomFilter, BloomFilter) {
+    let filters = Arc::new(StdMutex::new(
+        HashMap::<DhtId, (BloomFilter, BloomFilter)>::new(),
+    ));
+    let seeds = table.lock().unwrap().closest(&info_hash, K, Instant::now());
+    let closest = lookup(info_hash.clone(), seeds, ALPHA, K, |contact| {
+        let queue = queue.clone();
+        let udp = udp.clone();
+        let table = table.clone();
+        let info_hash = info_hash.clone();
+        let filters = filters.clone();
+        async move {
+            let reply = get_peers_query(
+                queue,
+                udp,
+                table.clone(),
+                contact.addr,
+                info_hash,
+                false,
+                true,
+            );
+            match reply.await {
+                Ok(reply) => {
+                    if let (Some(bf_sd), Some(bf_pe)) = (reply.bf_sd, reply.bf_pe) {
+                        filters.lock().unwrap().insert(contact.id, (bf_sd, bf_pe));
+                    }
+                    Ok(reply.nodes)
+                }
+                Err(e) => {
+                    table.lock().unwrap().query_failed(&contact.id);
+                    Err(e)
+                }
+            }
+        }
+    })
+    .await;
+
+    let filters = filters.lock().unwrap();
+    let mut bf_sd = BloomFilter::default();
+    let mut bf_pe = BloomFilter::default();
+    for (node_sd, node_pe) in closest
+        .iter()
+        .filter_map(|contact| filters.get(&contact.id))
+    {
+        bf_sd.merge(node_sd);
+        bf_pe.merge(node_pe);
+    }
+    (bf_sd, bf_pe)
+}
+
+/// Estimate the swarm size of the torrent, BEP 33, looking it up from
+/// each socket with its routing table.  Bloom filters of the closest
+/// nodes that have responded are merged, so peers known to several
+/// nodes are counted once.
+pub(crate) async fn scrape(
+    queue: Arc<QueryQueue>,
+    nodes: &[(Arc<UdpSocket>, Arc<StdMutex<RoutingTable>>)],
+    info_hash: DhtId,
+) -> ScrapeEstimate {
+    let lookups = nodes.iter().map(|(udp, table)| {
+        scrape_filters(queue.clone(), udp.clone(), table.clone(), info_hash.clone())
+    });
+    let mut bf_sd = BloomFilter::default();
+    let mut bf_pe = BloomFilter::default();
+    for (node_sd, node_pe) in future::join_all(lookups).await {
+        bf_sd.merge(&node_sd);
+        bf_pe.merge(&node_pe);
+    }
+    ScrapeEstimate {
+        seeds: bf_sd.estimate(),
+        leechers: bf_pe.estimate(),
+    }
+}
+
+/// Reply to a single `get` query.  `sig` and `seq` are present for
+/// mutable items.
+pub(crate) struct GetReply {
+    pub(crate) token: Vec<u8>,
+    pub(crate) v: Option<Value>,
+    pub(crate) sig: Option<Vec<u8>>,
+    pub(crate) seq: Option<i64>,
+    pub(crate) nodes: Vec<Contact>,
+}
+
+/// Send single `get` query, recording the responder in the routing
+/// table.
+pub(crate) async fn get_query(
+    queue: Arc<QueryQueue>,
+    udp: Arc<UdpSocket>,
+    table: Arc<StdMutex<RoutingTable>>,
+    addr: SocketAddr,
+    target: DhtId,
+) -> Result<GetReply, KrpcError> {
+    let self_id = table.lock().unwrap().self_id().clone();
+    let query = dht::Query::Get(dht::GetQuery {
+        id: self_id.clone(),
+        target,
+        seq: None,
+    });
+    let r: dht::GetResponse = queue.clone().query(udp.clone(), addr, query).await?;
+    record_reply(&queue, &udp, &table, Contact::new(r.id, addr));
+    Ok(GetReply {
+        token: r.token.into_owned(),
+        v: r.v,
+        sig: r.sig.map(Cow::into_owned),
+        seq: r.seq,
+        nodes: reply_contacts(&r.nodes, &r.nodes6, &self_id, &addr),
+    })
+}
+
+/// Iterative `get` lookup.  Items of the replies are checked with
+/// `verify`, and the newest verified one is returned.
+///
+/// Also returns the closest nodes that have responded, with tokens
+/// they gave us and the sequence numbers of the mutable items they
+/// hold.
+async fn get_lookup<F>(
+    queue: Arc<QueryQueue>,
+    udp: Arc<UdpSocket>,
+    table: Arc<StdMutex<RoutingTable>>,
+    target: DhtId,
+    verify: F,
+) -> (Option<Item>, Vec<(Contact, Vec<u8>, Option<i64>)>)
+where
+    F: Fn(&GetReply) -> Option<Item>,
+{
+    let found = StdMutex::new(None::<Item>);
+    let tokens = StdMutex::new(HashMap::<DhtId, (Vec<u8>, Option<i64>)>::new());
+    let seeds = table.lock().unwrap().closest(&target, K, Instant::now());
+    let closest = lookup(target.clone(), seeds, ALPHA, K, |contact| {
+        let res = get_query(
+            queue.clone(),
+            udp.clone(),
+            table.clone(),
+            contact.addr,
+            target.clone(),
+        );
+        let (table, found, tokens, verify) = (table.clone(), &found, &tokens, &verify);
+        async move {
+            match res.await {
+                Ok(reply) => {
+                    let item = verify(&reply);
+                    let seq = match &item {
+                        Some(Item::Mutable(item)) => Some(item.seq),
+                        _ => None,
+                    };
+                    if let Some(item) = item {
+                        let mut found = found.lock().unwrap();
+                        let newer = match (&*found, &item) {
+                            (None, _) => true,
+                            (Some(Item::Mutable(old)), Item::Mutable(new)) => new.seq > old.seq,
+                            _ => false,
+                        };
+                        if newer {
+                            *found = Some(item);
+                        }
+                    }
+                    tokens
+                        .lock()
+                        .unwrap()
+                        .insert(contact.id, (reply.token, seq));
+                    Ok(reply.nodes)
+                }
+                Err(e) => {
+                    table.lock().unwrap().query_failed(&contact.id);
+                    Err(e)
+                }
+            }
+        }
+    })
+    .await;
+
+    let mut tokens = tokens.into_inner().unwrap();
+    let closest = closest
+        .into_iter()
+        .filter_map(|contact| {
+            let (token, seq) = tokens.remove(&contact.id)?;
+            Some((contact, token, seq))
+        })
+        .collect();
+    (found.into_inner().unwrap(), closest)
+}
+
+/// Mutable item of the reply if its signature is valid for the key
+/// and salt.
+fn verified_mutable(
+    k: &[u8; bep_0044::PUBLIC_KEY_SIZE],
+    salt: &[u8],
+    reply: &GetReply,
+) -> Option<MutableItem> {
+    let mut item = MutableItem {
+        k: *k,
+        sig: [0; bep_0044::SIGNATURE_SIZE],
+        seq: reply.seq?,
+        salt: salt.to_vec(),
+        v: reply.v.clone()?,
+    };
+    let sig = reply.sig.as_ref()?;
+    if sig.len() != item.sig.len() {
+        return None;
+    }
+    item.sig.copy_from_slice(sig);
+    if item.verify() {
+        Some(item)
+    } else {
+        None
+    }
+}
+
+/// Fetch an immutable item.  Values that don't match the target are
+/// ignored.
+pub(crate) async fn get_immutable(
+    queue: Arc<QueryQueue>,
+    udp: Arc<UdpSocket>,
+    table: Arc<StdMutex<RoutingTable>>,
+    target: DhtId,
+) -> Option<Value> {
+    let verify = |reply: &GetReply| {
+        reply
+            .v
+            .clone()
+            .filter(|v| bep_0044::immutable_target(v) == target)
+            .map(Item::Immutable)
+    };
+    match get_lookup(queue, udp, table, target.clone(), verify)
+        .await
+        .0
+    {
+        Some(Item::Immutable(v)) => Some(v),
+        _ => None,
+    }
+}
+
+/// Fetch the newest version of a mutable item.  Items with invalid
+/// signatures are ignored.
+pub(crate) async fn get_mutable(
+    queue: Arc<QueryQueue>,
+    udp: Arc<UdpSocket>,
+    table: Arc<StdMutex<RoutingTable>>,
+    k: [u8; bep_0044::PUBLIC_KEY_SIZE],
+    salt: Vec<u8>,
+) -> Option<MutableItem> {
+    let target = bep_0044::mutable_target(&k, &salt);
+    let verify = |reply: &GetReply| verified_mutable(&k, &salt, reply).map(Item::Mutable);
+    match get_lookup(queue, udp, table, target, verify).await.0 {
+        Some(Item::Mutable(item)) => Some(item),
+        _ => None,
+    }
+}
+
+/// Send single `put` query.
+pub(crate) async fn put_query(
+    queue: Arc<QueryQueue>,
+    udp: Arc<UdpSocket>,
+    addr: SocketAddr,
+    query: dht::PutQuery<'static>,
+) -> Result<(), KrpcError> {
+    let query = dht::Query::Put(query);
+    queue.query::<dht::PutResponse>(udp, addr, query).await?;
+    Ok(())
+}
+
+/// Store an immutable item on the nodes closest to its target.  Fails
+/// if the value is too big.
+///
+/// Returns the target and number of nodes that have stored the item.
+pub(crate) async fn put_immutable(
+    queue: Arc<QueryQueue>,
+    udp: Arc<UdpSocket>,
+    table: Arc<StdMutex<RoutingTable>>,
+    v: Value,
+) -> Result<(DhtId, usize), ItemError> {
+    if v.encode().len() > bep_0044::MAX_VALUE_SIZE {
+        return Err(ItemError::ValueTooBig);
+    }
+    let target = bep_0044::immutable_target(&v);
+    let self_id = table.lock().unwrap().self_id().clone();
+    let (_, closest) =
+        get_lookup(queue.clone(), udp.clone(), table, target.clone(), |_| None).await;
+
+    let acks = future::join_all(closest.into_iter().map(|(contact, token, _)| {
+        put_query(
+            queue.clone(),
+            udp.clone(),
+            contact.addr,
+            dht::PutQuery::immutable(self_id.clone(), Cow::Owned(token), v.clone()),
+        )
+    }))
+    .await;
+    Ok((target, acks.into_iter().filter(Result::is_ok).count()))
+}
+
+/// Sign and store a new version of a mutable item on the nodes
+/// closest to its target, looked up from each socket with its routing
+/// table.  The sequence number of the newest version found is
+/// incremented.  Each node is sent the sequence number it holds as
+/// `cas`.  Fails if the value or the salt is too big.
+///
+/// Returns the item stored and number of nodes that have stored it.
+pub(crate) async fn put_mutable(
+    queue: Arc<QueryQueue>,
+    nodes: &[(Arc<UdpSocket>, Arc<StdMutex<RoutingTable>>)],
+    keypair: &Keypair,
+    salt: Vec<u8>,
+    v: Value,
+) -> Result<(MutableItem, usize), ItemError> {
+    if v.encode().len() > bep_0044::MAX_VALUE_SIZE {
+        return Err(ItemError::ValueTooBig);
+    }
+    if salt.len() > bep_0044::MAX_SALT_SIZE {
+        return Err(ItemError::SaltTooBig);
+    }
+    let k = keypair.public.to_bytes();
+    let target = bep_0044::mutable_target(&k, &salt);
+    let verify = |reply: &GetReply| verified_mutable(&k, &salt, reply).map(Item::Mutable);
+    let lookups = future::join_all(nodes.iter().map(|(udp, table)| {
+        get_lookup(
+            queue.clone(),
+            udp.clone(),
+            table.clone(),
+            target.clone(),
+            &verify,
+        )
+    }))
+    .await;
+
+    let newest = lookups
+        .iter()
+        .filter_map(|(found, _)| match found {
+            Some(Item::Mutable(item)) => Some(item.seq),
+            _ => None,
+        })
+        .max();
+    let item = MutableItem::new(keypair, salt, newest.map_or(1, |seq| seq + 1), v);
+    let mut puts = vec![];
+    for ((udp, table), (_, closest)) in nodes.iter().zip(lookups) {
+        let self_id = table.lock().unwrap().self_id().clone();
+        // An outdated node is updated too: its own version is `cas`.
+        for (contact, token, cas) in closest {
+            puts.push(put_query(
+                queue.clone(),
+                udp.clone(),
+                contact.addr,
+                dht::PutQuery::mutable(self_id.clone(), Cow::Owned(token), item.clone(), cas),
+            ));
+        }
+    }
+    let acks = future::join_all(puts).await;
+    Ok((item, acks.into_iter().filter(Result::is_ok).count()))
+}
+
+/// Point the updating torrent of our key and `salt` to the new info
+/// hash, BEP 46.  Returns the magnet link with the number of nodes that
+/// have stored the item.
+pub(crate) async fn publish_magnet(
+    queue: Arc<QueryQueue>,
+    nodes: &[(Arc<UdpSocket>, Arc<StdMutex<RoutingTable>>)],
+    keypair: &Keypair,
+    salt: Vec<u8>,
+    info_hash: &DhtId,
+) -> Result<(MagnetLink, usize), ItemError> {
+    let v = bep_0046::item_value(info_hash);
+    let (item, acks) = put_mutable(queue, nodes, keypair, salt, v).await?;
+    Ok((MagnetLink::new(item.k, item.salt), acks))
+}
+
+/// Reply to a single `sample_infohashes` query.
+pub(crate) struct SampleReply {
+    pub(crate) interval: Duration,
+    pub(crate) samples: Vec<DhtId>,
+    pub(crate) nodes: Vec<Contact>,
+}
+
+/// When each node may be sampled again, as requested by the
+/// `interval` of its last reply.
+#[derive(Default)]
+pub(crate) struct SampleSchedule {
+    next: HashMap<SocketAddr, Instant>,
+}
+
+impl SampleSchedule {
+    pub(crate) fn is_due(&self, addr: &SocketAddr, now: Instant) -> bool {
+        self.next.get(addr).map_or(true, |next| *next <= now)
+    }
+
+    pub(crate) fn sampled(&mut self, addr: SocketAddr, interval: Duration, now: Instant) {
+        self.next.retain(|_, next| *next > now);
+        self.next.insert(addr, now + interval);
+    }
+}
+
+/// Send single `sample_infohashes` query, recording the responder in
+/// the routing table.
+pub(crate) async fn sample_infohashes_query(
+    queue: Arc<QueryQueue>,
+    udp: Arc<UdpSocket>,
+    table: Arc<StdMutex<RoutingTable>>,
+    addr: SocketAddr,
+    target: DhtId,
+) -> Result<SampleReply, KrpcError> {
+    let self_id = table.lock().unwrap().self_id().clone();
+    let query = dht::Query::SampleInfohashes(dht::SampleInfohashesQuery {
+        id: self_id.clone(),
+        target,
+    });
+    let r: dht::SampleInfohashesResponse = queue.clone().query(udp.clone(), addr, query).await?;
+    record_reply(&queue, &udp, &table, Contact::new(r.id, addr));
+    Ok(SampleReply {
+        interval: Duration::from_secs(r.interval.into()),
+        samples: r.samples.iter().collect(),
+        nodes: reply_contacts(&r.nodes, &r.nodes6, &self_id, &addr),
+    })
+}
+
+/// Walk the keyspace with `sample_infohashes`, one lookup per top
+/// byte of the target.  Nodes that are not due according to the
+/// schedule are asked `find_node` instead, only to continue the walk.
+/// Samples are sent to the channel as they arrive, possibly with
+/// duplicates.
+pub(crate) async fn sample_infohashes_walk(
+    queue: Arc<QueryQueue>,
+    udp: Arc<UdpSocket>,
+    table: Arc<StdMutex<RoutingTable>>,
+    schedule: Arc<StdMutex<SampleSchedule>>,
+    samples: mpsc::UnboundedSender<DhtId>,
+) {
+    let targets: Vec<DhtId> = {
+        let mut rng = rand::thread_rng();
+        (0..=255u8)
+            .map(|prefix| {
+                let mut target = DhtId::default();
+                rand::Rng::fill(&mut rng, &mut target.0[..]);
+                target.0[0] = prefix;
+                target
+            })
+            .collect()
+    };
+
+    for target in targets {
+        if samples.is_closed() {
+            return;
+        }
+        let seeds = table.lock().unwrap().closest(&target, K, Instant::now());
+        lookup(target.clone(), seeds, ALPHA, K, |contact| {
+            let queue = queue.clone();
+            let udp = udp.clone();
+            let table = table.clone();
+            let schedule = schedule.clone();
+            let samples = samples.clone();
+            let target = target.clone();
+            async move {
+                let due = schedule
+                    .lock()
+                    .unwrap()
+                    .is_due(&contact.addr, Instant::now());
+                let res = if due {
+                    sample_infohashes_query(queue, udp, table.clone(), contact.addr, target)
+                        .await
+                        .map(|reply| {
+                            schedule.lock().unwrap().sampled(
+                                contact.addr,
+                                reply.interval,
+                                Instant::now(),
+                            );
+                            for info_hash in reply.samples {
+                                let _ = samples.send(info_hash);
+                            }
+                            reply.nodes
+                        })
+                } else {
+                    find_node_query(queue, udp, table.clone(), contact.addr, target).await
+                };
+                if res.is_err() {
+                    table.lock().unwrap().query_failed(&contact.id);
+                }
+                res
+            }
+        })
+        .await;
+    }
+}
+
+/// Stream of unique info hashes sampled from the whole network.  The
+/// walk runs in a separate task, and the stream ends when the walk is
+/// finished.
+pub(crate) fn sample_infohashes(
+    queue: Arc<QueryQueue>,
+    udp: Arc<UdpSocket>,
+    table: Arc<StdMutex<RoutingTable>>,
+    schedule: Arc<StdMutex<SampleSchedule>>,
+) -> impl Stream<Item = DhtId> {
+    let (send, recv) = mpsc::unbounded_channel();
+    tokio::task::spawn(sample_infohashes_walk(queue, udp, table, schedule, send));
+
+    stream::unfold((recv, HashSet::new()), |(mut recv, mut seen)| async move {
+        while let Some(info_hash) = recv.recv().await {
+            if seen.insert(info_hash.clone()) {
+                return Some((info_hash, (recv, seen)));
+            }
+        }
+        None
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn contact(b: u8) -> Contact {
+        let mut id = DhtId::default();
+        id.0[0] = b;
+        Contact::new(id, SocketAddrV4::new(Ipv4Addr::LOCALHOST, b as u16).into())
+    }
+
+    #[tokio::test]
+    async fn test_lookup_converges() {
+        // Every node knows two nodes closer to zero target.
+        let network: HashMap<u8, Vec<Contact>> = (1..=255u8)
+            .map(|b| (b, vec![contact(b / 2), contact(b / 3)]))
+            .collect();
+
+        let found = lookup(DhtId::default(), vec![contact(255)], ALPHA, 4, |c| {
+            let known = network[&c.id.0[0]].clone();
+            async move { Ok::<_, ()>(known.into_iter().filter(|c| c.id.0[0] != 0).collect()) }
+        })
+        .await;
+
+        assert_eq!(found, vec![contact(1), contact(2), contact(3), contact(4)]);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_skips_failed() {
+        let found = lookup(
+            DhtId::default(),
+            vec![contact(1), contact(2), contact(3)],
+            ALPHA,
+            2,
+            |c| async move {
+                if c.id.0[0] == 1 {
+                    Err(())
+                } else {
+                    Ok(vec![])
+                }
+            },
+        )
+        .await;
+
+        assert_eq!(found, vec![contact(2), contact(3)]);
+    }
+
+    fn server(table: Arc<StdMutex<RoutingTable>>) -> crate::server::Server {
+        let id = table.lock().unwrap().self_id().clone();
+        let table6 = RoutingTable::new(id.clone());
+        crate::server::Server::new(id, table, Arc::new(StdMutex::new(table6)))
+    }
+
+    /// A client node that knows a single server node, both running on
+    /// the loopback interface.
+    async fn client_and_server() -> (Arc<QueryQueue>, Arc<UdpSocket>, Arc<StdMutex<RoutingTable>>) {
+        let localhost: SocketAddr = (std::net::Ipv4Addr::LOCALHOST, 0).into();
+
+        let server_table = Arc::new(StdMutex::new(RoutingTable::new(DhtId([1; 20]))));
+        let server_udp = Arc::new(UdpSocket::bind(localhost).await.unwrap());
+        let server_addr = server_udp.local_addr().unwrap();
+        let server_queue = Arc::new(QueryQueue::new(RetryPolicy::once(
+            std::time::Duration::from_secs(1),
+        )));
+        tokio::task::spawn(async move { server(server_table).run(server_udp, server_queue).await });
+
+        let table = Arc::new(StdMutex::new(RoutingTable::new(DhtId([2; 20]))));
+        let udp = Arc::new(UdpSocket::bind(localhost).await.unwrap());
+        let queue = Arc::new(QueryQueue::new(RetryPolicy::once(
+            std::time::Duration::from_secs(1),
+        )));
+        {
+            let table = table.clone();
+            let udp = udp.clone();
+            let queue = queue.clone();
+            tokio::task::spawn(async move { server(table).run(udp, queue).await });
+        }
+        table
+            .lock()
+            .unwrap()
+            .node_replied(Contact::new(DhtId([1; 20]), server_addr), Instant::now());
+        (queue, udp, table)
+    }
+
+    #[tokio::test]
+    async fn test_stale_node_replaced() {
+        let localhost: SocketAddr = (std::net::Ipv4Addr::LOCALHOST, 0).into();
+        let server_table = Arc::new(StdMutex::new(RoutingTable::new(DhtId([1; 20]))));
+        let server_udp = Arc::new(UdpSocket::bind(localhost).await.unwrap());
+        let server_addr = server_udp.local_addr().unwrap();
+        let server_queue = Arc::new(QueryQueue::new(RetryPolicy::default()));
+        tokio::task::spawn(async move { server(server_table).run(server_udp, server_queue).await });
+
+        // The bucket of the server is full of nodes that stopped
+        // responding long ago.
+        let table = Arc::new(StdMutex::new(RoutingTable::new(DhtId([2; 20]))));
+        let stale = Instant::now()
+            .checked_sub(Duration::from_secs(60 * 60))
+            .unwrap();
+        for n in 0..K as u8 {
+            let mut id = DhtId([1; 20]);
+            id.0[19] = 0x80 + n;
+            let dead = std::net::UdpSocket::bind(localhost).unwrap();
+            let contact = Contact::new(id, dead.local_addr().unwrap());
+            table.lock().unwrap().node_replied(contact, stale);
+        }
+        let udp = Arc::new(UdpSocket::bind(localhost).await.unwrap());
+        let queue = Arc::new(QueryQueue::new(RetryPolicy::once(Duration::from_millis(
+            50,
+        ))));
+        {
+            let table = table.clone();
+            let udp = udp.clone();
+            let queue = queue.clone();
+            tokio::task::spawn(async move { server(table).run(udp, queue).await });
+        }
+
+        let retry = queue.retry_policy();
+        ping_query(queue, udp, table.clone(), server_addr, retry)
+            .await
+            .unwrap();
+        let server_contact = Contact::new(DhtId([1; 20]), server_addr);
+        for _ in 0..100 {
+            if table
+                .lock()
+                .unwrap()
+                .contacts()
+                .any(|c| c == &server_contact)
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let table = table.lock().unwrap();
+        assert!(table.contacts().any(|c| c == &server_contact));
+        assert_eq!(table.len(), K);
+    }
+
+    #[tokio::test]
+    async fn test_announce_and_get_peers() {
+        let (queue, udp, table) = client_and_server().await;
+        let client_addr = udp.local_addr().unwrap();
+
+        let info_hash = DhtId([3; 20]);
+        let acks = announce(
+            queue.clone(),
+            udp.clone(),
+            table.clone(),
+            info_hash.clone(),
+            0,
+            true,
+            false,
+        )
+        .await;
+        assert_eq!(acks, 1);
+
+        let peers: Vec<_> = get_peers(queue, udp, table, info_hash, false)
+            .collect()
+            .await;
+        assert_eq!(peers, vec![client_addr]);
+    }
+
+    #[tokio::test]
+    async fn test_put_and_get_immutable() {
+        let (queue, udp, table) = client_and_server().await;
+
+        let v = Value::Bytes(b"Hello World!".to_vec());
+        let (target, acks) = put_immutable(queue.clone(), udp.clone(), table.clone(), v.clone())
+            .await
+            .unwrap();
+        assert_eq!(acks, 1);
+
+        let found = get_immutable(queue.clone(), udp.clone(), table.clone(), target).await;
+        assert_eq!(found, Some(v));
+
+        let too_big = Value::Bytes(vec![0; bep_0044::MAX_VALUE_SIZE]);
+        assert_eq!(
+            put_immutable(queue, udp, table, too_big).await,
+            Err(ItemError::ValueTooBig)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_put_and_get_mutable() {
+        let (queue, udp, table) = client_and_server().await;
+        let keypair = Keypair::generate(&mut dht::init_chacha());
+        let k = keypair.public.to_bytes();
+        let salt = b"foobar".to_vec();
+
+        for n in 1..=2 {
+            let (item, acks) = put_mutable(
+                queue.clone(),
+                &[(udp.clone(), table.clone())],
+                &keypair,
+                salt.clone(),
+                Value::Int(n),
+            )
+            .await
+            .unwrap();
+            assert_eq!(acks, 1);
+            assert_eq!(item.seq, n);
+        }
+
+        let found = get_mutable(queue.clone(), udp.clone(), table.clone(), k, salt)
+            .await
+            .unwrap();
+        assert_eq!(found.seq, 2);
+        assert_eq!(found.v, Value::Int(2));
+
+        // Other salt is other item.
+        assert_eq!(get_mutable(queue, udp, table, k, vec![]).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_put_mutable_outdated_node() {
+        let (queue, udp, table) = client_and_server().await;
+        let first = table.lock().unwrap().contacts().next().unwrap().clone();
+        let localhost: SocketAddr = (std::net::Ipv4Addr::LOCALHOST, 0).into();
+        let second_table = Arc::new(StdMutex::new(RoutingTable::new(DhtId([3; 20]))));
+        let second_udp = Arc::new(UdpSocket::bind(localhost).await.unwrap());
+        let second_addr = second_udp.local_addr().unwrap();
+        let second_queue = Arc::new(QueryQueue::new(RetryPolicy::default()));
+        tokio::task::spawn(async move { server(second_table).run(second_udp, second_queue).await });
+        table
+            .lock()
+            .unwrap()
+            .node_replied(Contact::new(DhtId([3; 20]), second_addr), Instant::now());
+        let first_only = Arc::new(StdMutex::new(RoutingTable::new(DhtId([2; 20]))));
+        first_only
+            .lock()
+            .unwrap()
+            .node_replied(first, Instant::now());
+
+        let keypair = Keypair::generate(&mut dht::init_chacha());
+        let put = |table: Arc<StdMutex<RoutingTable>>, n| {
+            let (queue, udp) = (queue.clone(), udp.clone());
+            let keypair = Keypair::from_bytes(&keypair.to_bytes()).unwrap();
+            async move {
+                put_mutable(queue, &[(udp, table)], &keypair, vec![], Value::Int(n))
+                    .await
+                    .unwrap()
+            }
+        };
+        assert_eq!(put(table.clone(), 1).await.1, 2);
+        // The second server misses version 2, and holds version 1.
+        assert_eq!(put(first_only, 2).await.1, 1);
+        let (item, acks) = put(table, 3).await;
+        assert_eq!((item.seq, acks), (3, 2));
+    }
+
+    #[tokio::test]
+    async fn test_scrape() {
+        let (queue, udp, table) = client_and_server().await;
+        let info_hash = DhtId([3; 20]);
+
+        let estimate = scrape(
+            queue.clone(),
+            &[(udp.clone(), table.clone())],
+            info_hash.clone(),
+        )
+        .await;
+        assert_eq!(estimate.seeds.round(), 0.0);
+
+        let acks = announce(
+            queue.clone(),
+            udp.clone(),
+            table.clone(),
+            info_hash.clone(),
+            0,
+            true,
+            true,
+        )
+        .await;
+        assert_eq!(acks, 1);
+
+        let estimate = scrape(
+            queue.clone(),
+            &[(udp.clone(), table.clone())],
+            info_hash.clone(),
+        )
+        .await;
+        assert_eq!(estimate.seeds.round(), 1.0);
+        assert_eq!(estimate.leechers.round(), 0.0);
+
+        // Seeds are not returned with `noseed`.
+        let peers: Vec<_> = get_peers(queue, udp, table, info_hash, true)
+            .collect()
+            .await;
+        assert_eq!(peers, vec![]);
+    }
+
+    #[tokio::test]
+    async fn test_sample_infohashes() {
+        let (queue, udp, table) = client_and_server().await;
+        let info_hash = DhtId([3; 20]);
+        let acks = announce(
+            queue.clone(),
+            udp.clone(),
+            table.clone(),
+            info_hash.clone(),
+            0,
+            true,
+            false,
+        )
+        .await;
+        assert_eq!(acks, 1);
+
+        let schedule = Arc::new(StdMutex::new(SampleSchedule::default()));
+        let sample = |schedule| {
+            sample_infohashes(queue.clone(), udp.clone(), table.clone(), schedule)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sample(schedule.clone()).await, vec![info_hash]);
+        // The server asked to wait before sampling it again.
+        assert_eq!(sample(schedule).await, vec![]);
+    }
+}
@@ -1,180 +1,422 @@
-use std::net::SocketAddr;
-use std::path::Path;
-use std::sync::Arc;
+use du_has_t::{Contact, Dht, DhtBuilder, DhtId, RetryPolicy};
+use futures::StreamExt;
+use serde::Serialize;
+use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
-use tokio::net::UdpSocket;
 
-mod bep_0042;
-mod dht;
-mod query_queue;
+const USAGE: &str = "\
+Usage: du_has_t [OPTIONS] <COMMAND>
 
-#[tokio::main]
-async fn main() {
-    // We do not bother with async read/write for config.
-    let mut chacha = dht::init_chacha();
+Commands:
+  run                          Run the node until interrupted
+  ping <addr>                  Ping a node
+  find-node <addr> <id>        Ask a node for the nodes closest to the id
+  get-peers <info-hash>        Look up peers of a torrent
+  announce <info-hash> <port>  Announce us as a peer of a torrent
+  id generate --ip <ip>        Generate a node id valid for the address
+  id check <id> --ip <ip>      Check the node id is valid for the address
+  table dump                   Bootstrap and print the routing table
 
-    let results = Arc::new(std::sync::Mutex::new(Vec::<(dht::DhtId, SocketAddr)>::new()));
-    let results1 = results.clone();
+Options:
+  --bind <addr>       Address to bind, may be repeated
+  --state <path>      State file of the node
+  --bootstrap <addr>  Node to bootstrap from, may be repeated
+  --timeout <secs>    Time to wait for the first reply, up to an hour
+  --attempts <count>  Times a query is sent, waiting twice as long each time
+  --read-only         Don't answer queries, BEP 43
+  --seed              Announce as a seed
+  --ip <ip>           External address of the node
+  --format <format>   Output format: text or json
+";
 
-    let local = tokio::net::lookup_host("192.168.0.26:4242")
-        .await
-        .unwrap()
-        .next()
-        .unwrap();
-
-    let cfg = if Path::new(dht::DEFAULT_STATE_PATH).exists() {
-        dht::Config::load(dht::DEFAULT_STATE_PATH).unwrap()
-    } else {
-        dht::Config::new(&mut chacha, local.ip())
-    };
-    cfg.write(dht::DEFAULT_STATE_PATH).unwrap();
-    println!("{}", cfg.dht_id);
+/// Public routers bootstrapped from unless `--bootstrap` or the state
+/// file gives other nodes.
+const BOOTSTRAP_ROUTERS: &[&str] = &[
+    "router.bittorrent.com:6881",
+    "dht.transmissionbt.com:6881",
+    "router.utorrent.com:6881",
+];
+
+/// Longest `--timeout` accepted, an hour.
+const MAX_TIMEOUT_SECS: f64 = 3600.0;
 
-    let self_id1 = cfg.dht_id.clone();
-    let self_id2 = cfg.dht_id.clone();
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Format {
+    Text,
+    Json,
+}
 
-    let udp = Arc::new(UdpSocket::bind(local).await.unwrap());
-    let udp1 = udp.clone();
-    let udp2 = udp.clone();
+#[derive(Debug, PartialEq)]
+enum Command {
+    Run,
+    Ping(String),
+    FindNode(String, DhtId),
+    GetPeers(DhtId),
+    Announce(DhtId, u16),
+    IdGenerate,
+    IdCheck(DhtId),
+    TableDump,
+}
 
-    let qq = Arc::new(crate::query_queue::QueryQueue::new(Duration::from_secs(1)));
-    let qq1 = qq.clone();
-    let qq2 = qq.clone();
+#[derive(Debug, PartialEq)]
+struct Options {
+    command: Command,
+    bind: Vec<String>,
+    state: Option<String>,
+    bootstrap: Vec<String>,
+    timeout: Option<Duration>,
+    attempts: Option<u32>,
+    read_only: bool,
+    seed: bool,
+    ip: Option<IpAddr>,
+    format: Format,
+}
 
-    let remote = tokio::net::lookup_host("192.168.0.26:7881")
-        .await
-        .unwrap()
-        .next()
-        .unwrap();
-    let remote1 = remote.clone();
-    let remote2 = remote.clone();
-
-    tokio::task::spawn(async move {
-        // form a message 1
-        let msg1 = dht::Message::<()>::Q(dht::Query::FindNode(dht::FindNodeQuery {
-            id: self_id1.clone(),
-            // target: dht::DhtId::new(&mut chacha),
-            target: self_id1.clone(),
-        }));
-
-        let qq11 = qq1.clone();
-        let udp11 = udp1.clone();
-        match qq1.send_message(udp1, remote1, msg1).await {
-            Ok(resp) => {
-                let msg =
-                    serde_bencoded::from_bytes_auto::<dht::Message<dht::FindNodeResponse>>(&resp)
-                        .unwrap();
-                eprintln!("{:?}", msg);
-                if let dht::Message::R {
-                    r: dht::FindNodeResponse { id: _, nodes },
-                } = &msg
-                {
-                    {
-                        let mut results = results1.lock().unwrap();
-                        for node in nodes.iter() {
-                            results.push((node.id.clone(), (node.ip, node.port).into()));
-                        }
-                    }
-                    for node in nodes.iter() {
-                        let results = results1.clone();
-                        let udp11 = udp11.clone();
-                        let qq11 = qq11.clone();
-                        let self_id1 = self_id1.clone();
-                        eprintln!("Send to node: {:?}:{}", node.ip, node.port);
-                        let ip = node.ip;
-                        let port = node.port;
-
-                        let msg1 =
-                            dht::Message::<()>::Q(dht::Query::FindNode(dht::FindNodeQuery {
-                                id: self_id1.clone(),
-                                // target: dht::DhtId::new(&mut chacha),
-                                target: self_id1.clone(),
-                            }));
-                        tokio::task::spawn(async move {
-                            eprintln!("Send to node: {:?}:{}", node.ip, node.port);
-                            match qq11
-                                .clone()
-                                .send_message(udp11.clone(), (ip, port).into(), msg1)
-                                .await
-                            {
-                                Ok(resp) => {
-                                    let msg = serde_bencoded::from_bytes_auto::<
-                                        dht::Message<dht::FindNodeResponse>,
-                                    >(&resp)
-                                    .unwrap();
-                                    eprintln!("{:?}", msg);
-                                    if let dht::Message::R {
-                                        r: dht::FindNodeResponse { id: _, nodes },
-                                    } = &msg
-                                    {
-                                        {
-                                            let mut results = results.lock().unwrap();
-                                            for node in nodes.iter() {
-                                                results.push((
-                                                    node.id.clone(),
-                                                    (node.ip, node.port).into(),
-                                                ));
-                                            }
-                                        }
-                                    }
-                                }
-                                Err(_) => eprintln!("ERROR"),
-                            }
-                        });
-                    }
+fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Options, String> {
+    let mut positional = vec![];
+    let mut opts = Options {
+        command: Command::Run,
+        bind: vec![],
+        state: None,
+        bootstrap: vec![],
+        timeout: None,
+        attempts: None,
+        read_only: false,
+        seed: false,
+        ip: None,
+        format: Format::Text,
+    };
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or(format!("{} requires a value", arg));
+        match arg.as_str() {
+            "--bind" => opts.bind.push(value()?),
+            "--state" => opts.state = Some(value()?),
+            "--bootstrap" => opts.bootstrap.push(value()?),
+            "--timeout" => match value()?.parse::<f64>() {
+                // Also rejects NaN.
+                Ok(secs) if secs > 0.0 && secs <= MAX_TIMEOUT_SECS => {
+                    opts.timeout = Some(Duration::from_secs_f64(secs))
+                }
+                _ => return Err("malformed timeout".to_owned()),
+            },
+            "--attempts" => match value()?.parse() {
+                Ok(attempts) if attempts > 0 => opts.attempts = Some(attempts),
+                _ => return Err("malformed attempts".to_owned()),
+            },
+            "--read-only" => opts.read_only = true,
+            "--seed" => opts.seed = true,
+            "--ip" => opts.ip = Some(value()?.parse().map_err(|_| "malformed ip")?),
+            "--format" => {
+                opts.format = match value()?.as_str() {
+                    "text" => Format::Text,
+                    "json" => Format::Json,
+                    other => return Err(format!("unknown format {}", other)),
                 }
             }
-            Err(_) => eprintln!("ERROR"),
+            other if other.starts_with("--") => return Err(format!("unknown option {}", other)),
+            _ => positional.push(arg),
+        }
+    }
+
+    let id = |s: &str| s.parse::<DhtId>().map_err(|e| format!("{}: {}", s, e));
+    let positional: Vec<&str> = positional.iter().map(String::as_str).collect();
+    opts.command = match positional[..] {
+        ["run"] => Command::Run,
+        ["ping", addr] => Command::Ping(addr.to_owned()),
+        ["find-node", addr, target] => Command::FindNode(addr.to_owned(), id(target)?),
+        ["get-peers", info_hash] => Command::GetPeers(id(info_hash)?),
+        ["announce", info_hash, port] => Command::Announce(
+            id(info_hash)?,
+            port.parse()
+                .map_err(|_| format!("malformed port {}", port))?,
+        ),
+        ["id", "generate"] => Command::IdGenerate,
+        ["id", "check", node_id] => Command::IdCheck(id(node_id)?),
+        ["table", "dump"] => Command::TableDump,
+        [] => return Err("no command".to_owned()),
+        _ => return Err(format!("unknown command {}", positional.join(" "))),
+    };
+    if matches!(opts.command, Command::IdGenerate | Command::IdCheck(_)) && opts.ip.is_none() {
+        return Err("--ip is required".to_owned());
+    }
+    Ok(opts)
+}
+
+#[derive(Serialize)]
+struct NodeOutput {
+    id: String,
+    addr: String,
+}
+
+impl From<&Contact> for NodeOutput {
+    fn from(contact: &Contact) -> Self {
+        Self {
+            id: contact.id.to_string(),
+            addr: contact.addr.to_string(),
         }
-    });
-
-    tokio::task::spawn(async move {
-        // form a message 2
-        let msg2 = dht::Message::<()>::Q(dht::Query::GetPeers(dht::GetPeersQuery {
-            id: self_id2,
-            // target: dht::DhtId::new(&mut chacha),
-            info_hash: dht::DhtId::from_str("4175EF7E2691D08AA4DC6B848E35DF84E8FE175B").unwrap(),
-        }));
-
-        match qq2.send_message(udp2, remote2, msg2).await {
-            Ok(resp) => {
-                let msg =
-                    serde_bencoded::from_bytes_auto::<dht::Message<dht::FindNodeResponse>>(&resp)
-                        .unwrap();
-                eprintln!("{:?}", msg);
+    }
+}
+
+#[derive(Serialize)]
+struct AnnounceOutput {
+    info_hash: String,
+    port: u16,
+    nodes: usize,
+}
+
+#[derive(Serialize)]
+struct IdOutput {
+    id: String,
+    ip: IpAddr,
+    valid: bool,
+}
+
+fn print_json<T: Serialize>(value: &T) {
+    println!(
+        "{}",
+        serde_json::to_string(value).expect("output is always serializable")
+    );
+}
+
+fn print_nodes(format: Format, nodes: &[Contact]) {
+    match format {
+        Format::Text => {
+            for node in nodes {
+                println!("{} {}", node.id, node.addr);
             }
-            Err(_) => eprintln!("ERROR"),
         }
-    });
+        Format::Json => print_json(&nodes.iter().map(NodeOutput::from).collect::<Vec<_>>()),
+    }
+}
 
-    let sleep = tokio::time::sleep(Duration::from_secs(20));
-    tokio::pin!(sleep);
+fn fail(message: &str) -> ! {
+    eprintln!("ERROR: {}", message);
+    std::process::exit(1);
+}
 
-    for _ in 0u8..200 {
-        let mut data = vec![0u8; 1 << 16];
+async fn resolve(addr: &str) -> SocketAddr {
+    match tokio::net::lookup_host(addr)
+        .await
+        .map(|mut addrs| addrs.next())
+    {
+        Ok(Some(addr)) => addr,
+        _ => fail(&format!("cannot resolve {}", addr)),
+    }
+}
 
-        tokio::select! {
-            res = udp.recv_from(&mut data) => {
-                let (len, from) = res.unwrap();
-                data.resize(len, 0);
+async fn build(opts: &Options) -> Dht {
+    let daemon = opts.command == Command::Run;
+    // One-off commands use fresh ids, and must not disturb a running
+    // daemon's port and state.
+    let mut builder = DhtBuilder::new().with_read_only(opts.read_only || !daemon);
+    if opts.bind.is_empty() && !daemon {
+        builder = builder
+            .with_bind_address("0.0.0.0:0")
+            .with_bind_address("[::]:0");
+    }
+    for address in &opts.bind {
+        builder = builder.with_bind_address(address.as_str());
+    }
+    builder = match &opts.state {
+        Some(path) => builder.with_state_path(path),
+        None if !daemon => builder.without_state_file(),
+        None => builder,
+    };
+    builder = builder.with_default_bootstrap_nodes(BOOTSTRAP_ROUTERS.iter().copied());
+    if !opts.bootstrap.is_empty() {
+        builder = builder.with_bootstrap_nodes(opts.bootstrap.iter().map(String::as_str));
+    }
+    let default = RetryPolicy::default();
+    let retry = RetryPolicy::new(
+        opts.attempts.unwrap_or(default.attempts()),
+        opts.timeout.unwrap_or(default.timeout()),
+        default.backoff(),
+    );
+    builder = match retry {
+        Ok(retry) => builder.with_retry_policy(retry),
+        Err(e) => fail(&e.to_string()),
+    };
+    match builder.build().await {
+        Ok(dht) => dht,
+        Err(e) => fail(&e.to_string()),
+    }
+}
 
-                let resp: dht::IncomingMessage = serde_bencoded::from_bytes_auto(&data[..len]).unwrap();
-                let id = query_queue::QueryId::from_be_bytes([resp.t[0], resp.t[1]]);
+async fn bootstrap(dht: &Dht) {
+    let nodes = dht.bootstrap().await;
+    eprintln!("Bootstrapped with {} nodes", nodes);
+}
 
-                if (resp.y == "r") | (resp.y == "e") {
-                    qq.got_reply(from, id, data);
-                } else {
-                    // TODO We should reply with some kind of error.
-                    eprintln!("WARNING: ignoring yet message with y={}", resp.y);
+#[tokio::main]
+async fn main() {
+    let opts = match parse_args(std::env::args().skip(1)) {
+        Ok(opts) => opts,
+        Err(e) => {
+            eprintln!("ERROR: {}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let format = opts.format;
+
+    // These don't need the network.
+    match (&opts.command, opts.ip) {
+        (Command::IdGenerate, Some(ip)) => {
+            let id = DhtId::generate(ip);
+            match format {
+                Format::Text => println!("{}", id),
+                Format::Json => print_json(&IdOutput {
+                    id: id.to_string(),
+                    ip,
+                    valid: true,
+                }),
+            }
+            return;
+        }
+        (Command::IdCheck(id), Some(ip)) => {
+            let valid = id.is_valid_for(ip);
+            match format {
+                Format::Text => println!("{}", if valid { "valid" } else { "invalid" }),
+                Format::Json => print_json(&IdOutput {
+                    id: id.to_string(),
+                    ip,
+                    valid,
+                }),
+            }
+            std::process::exit(if valid { 0 } else { 1 });
+        }
+        _ => {}
+    }
+
+    let dht = build(&opts).await;
+    match &opts.command {
+        Command::Run => {
+            let nodes: Vec<_> = dht
+                .local_addrs()
+                .into_iter()
+                .map(|(addr, id)| Contact { id, addr })
+                .collect();
+            print_nodes(format, &nodes);
+            bootstrap(&dht).await;
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                eprintln!("WARNING: cannot wait for Ctrl-C: {}", e);
+            }
+        }
+        Command::Ping(addr) => {
+            let addr = resolve(addr).await;
+            match dht.ping(addr).await {
+                Ok(id) => print_nodes(format, &[Contact { id, addr }]),
+                Err(e) => fail(&format!("{}: {}", addr, e)),
+            }
+        }
+        Command::FindNode(addr, target) => {
+            let addr = resolve(addr).await;
+            match dht.find_node_at(addr, target.clone()).await {
+                Ok(nodes) => print_nodes(format, &nodes),
+                Err(e) => fail(&format!("{}: {}", addr, e)),
+            }
+        }
+        Command::GetPeers(info_hash) => {
+            bootstrap(&dht).await;
+            let peers = dht.get_peers(info_hash.clone());
+            tokio::pin!(peers);
+            let mut found = vec![];
+            while let Some(peer) = peers.next().await {
+                match format {
+                    Format::Text => println!("{}", peer),
+                    Format::Json => found.push(peer.to_string()),
                 }
             }
-            _ = &mut sleep => { break }
+            if format == Format::Json {
+                print_json(&found);
+            }
         }
+        Command::Announce(info_hash, port) => {
+            bootstrap(&dht).await;
+            let nodes = dht
+                .announce(info_hash.clone(), Some(*port), opts.seed)
+                .await;
+            match format {
+                Format::Text => println!("Announced to {} nodes", nodes),
+                Format::Json => print_json(&AnnounceOutput {
+                    info_hash: info_hash.to_string(),
+                    port: *port,
+                    nodes,
+                }),
+            }
+        }
+        Command::TableDump => {
+            bootstrap(&dht).await;
+            let mut contacts = dht.contacts();
+            contacts.sort_by(|a, b| a.id.cmp(&b.id));
+            print_nodes(format, &contacts);
+        }
+        Command::IdGenerate | Command::IdCheck(_) => unreachable!(),
+    }
+
+    if let Err(e) = dht.shutdown().await {
+        eprintln!("WARNING: failed to save the state: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Options, String> {
+        parse_args(args.split_whitespace().map(str::to_owned))
+    }
+
+    #[test]
+    fn test_parse() {
+        let opts = parse("--bind 0.0.0.0:6881 run --bind [::]:6881 --read-only").unwrap();
+        assert_eq!(opts.command, Command::Run);
+        assert_eq!(opts.bind, vec!["0.0.0.0:6881", "[::]:6881"]);
+        assert!(opts.read_only);
+        assert_eq!(opts.format, Format::Text);
+
+        let opts =
+            parse("announce 4175ef7e2691d08aa4dc6b848e35df84e8fe175b 6881 --seed --format json")
+                .unwrap();
+        assert_eq!(
+            opts.command,
+            Command::Announce(
+                "4175ef7e2691d08aa4dc6b848e35df84e8fe175b".parse().unwrap(),
+                6881
+            )
+        );
+        assert!(opts.seed);
+        assert_eq!(opts.format, Format::Json);
+
+        let opts =
+            parse("id check 5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401 --ip 124.31.75.21").unwrap();
+        assert_eq!(opts.ip, Some([124, 31, 75, 21].into()));
+        assert_eq!(
+            parse("--timeout 2.5 ping router.bittorrent.com:6881")
+                .unwrap()
+                .timeout,
+            Some(Duration::from_millis(2500))
+        );
+        assert_eq!(parse("--attempts 3 run").unwrap().attempts, Some(3));
     }
 
-    let mut res = results.lock().unwrap();
-    (&mut res[..]).sort();
-    for (id, addr) in res.iter() {
-        eprintln!("{:?} {:?}", id, addr);
+    #[test]
+    fn test_parse_errors() {
+        assert!(parse("").is_err());
+        assert!(parse("frobnicate").is_err());
+        assert!(parse("ping").is_err());
+        assert!(parse("get-peers 4175").is_err());
+        assert!(parse("announce 4175ef7e2691d08aa4dc6b848e35df84e8fe175b port").is_err());
+        assert!(parse("id generate").is_err());
+        assert!(parse("run --format xml").is_err());
+        assert!(parse("run --bind").is_err());
+        assert!(parse("run --verbose").is_err());
+        assert!(parse("run --attempts 0").is_err());
+        assert!(parse("run --timeout 0").is_err());
+        assert!(parse("run --timeout -1").is_err());
+        assert!(parse("run --timeout nan").is_err());
+        assert!(parse("run --timeout inf").is_err());
+        assert!(parse("run --timeout 1e30").is_err());
     }
 }
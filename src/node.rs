@@ -0,0 +1,1287 @@
+//! The running node: a builder binding the sockets, and a cloneable
+//! handle to issue queries with.
+use crate::bep_0044::{self, ItemError, MutableItem, Value};
+use crate::bep_0046::{self, MagnetLink};
+use crate::dht::{self, Config, DhtId, StateError};
+use crate::lookup::{self, ScrapeEstimate};
+use crate::query_queue::{KrpcError, QueryQueue, RetryPolicy};
+use crate::routing_table::{Contact, RoutingTable, K};
+use crate::server::Server;
+use futures::future;
+use futures::stream::{self, Stream, StreamExt};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashSet;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::Mutex as StdMutex;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+
+/// Addresses bound when none is given.
+const DEFAULT_ADDRESSES: &[&str] = &["0.0.0.0:6881", "[::]:6881"];
+const DEFAULT_BOOTSTRAP_RETRIES: u32 = 4;
+const DEFAULT_BOOTSTRAP_BACKOFF: Duration = Duration::from_secs(2);
+const DEFAULT_SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// How often the routing tables are checked for buckets to refresh.
+const REFRESH_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+pub struct DhtBuilder {
+    addresses: Vec<String>,
+    state_path: Option<PathBuf>,
+    /// Replaces the list of the state file if set.
+    bootstrap: Option<Vec<String>>,
+    /// Used if the state file has no list.
+    default_bootstrap: Vec<String>,
+    bootstrap_retries: u32,
+    bootstrap_backoff: Duration,
+    save_interval: Duration,
+    retry: RetryPolicy,
+    read_only: bool,
+}
+
+impl DhtBuilder {
+    pub fn new() -> Self {
+        Self {
+            addresses: vec![],
+            state_path: Some(dht::DEFAULT_STATE_PATH.into()),
+            bootstrap: None,
+            default_bootstrap: vec![],
+            bootstrap_retries: DEFAULT_BOOTSTRAP_RETRIES,
+            bootstrap_backoff: DEFAULT_BOOTSTRAP_BACKOFF,
+            save_interval: DEFAULT_SAVE_INTERVAL,
+            retry: RetryPolicy::default(),
+            read_only: false,
+        }
+    }
+
+    /// Add a `host:port` address to bind.  Each address is a separate
+    /// node identity with its own id and routing table, BEP 45.
+    pub fn with_bind_address<S: Into<String>>(mut self, address: S) -> Self {
+        self.addresses.push(address.into());
+        self
+    }
+
+    /// File the node id and keys are kept in.
+    pub fn with_state_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.state_path = Some(path.into());
+        self
+    }
+
+    /// Neither load nor save the state: the node gets fresh ids, e.g.
+    /// for one-off queries.
+    pub fn without_state_file(mut self) -> Self {
+        self.state_path = None;
+        self
+    }
+
+    /// `host:port` addresses of the nodes to bootstrap from, replacing
+    /// the list of the state file.  They are not saved to it.
+    pub fn with_bootstrap_nodes<I, S>(mut self, nodes: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.bootstrap = Some(nodes.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// `host:port` addresses of the nodes to bootstrap from when neither
+    /// `with_bootstrap_nodes` nor the state file gives any, e.g. public
+    /// routers.  There are none by default, and they are not saved.
+    pub fn with_default_bootstrap_nodes<I, S>(mut self, nodes: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.default_bootstrap = nodes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Retry the bootstrap up to `retries` times while the routing
+    /// tables have fewer than K good nodes.  The first retry is after
+    /// `backoff`, and the delay doubles with each one.
+    pub fn with_bootstrap_retries(mut self, retries: u32, backoff: Duration) -> Self {
+        self.bootstrap_retries = retries;
+        self.bootstrap_backoff = backoff;
+        self
+    }
+
+    /// How often the routing tables are saved to the state file, in
+    /// addition to the shutdown.  It must not be zero.
+    pub fn with_save_interval(mut self, interval: Duration) -> Self {
+        self.save_interval = interval;
+        self
+    }
+
+    /// Time to wait for a reply to the first attempt of a query.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.retry = self.retry.with_timeout(timeout);
+        self
+    }
+
+    /// How queries are retransmitted when no reply comes.  Only pings
+    /// may be given their own policy, with `Dht::ping_with_retry`.  The
+    /// default sends a query twice, waiting 1 s and then 2 s.
+    pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+        self.retry = retry;
+        self
+    }
+
+    /// Run as a read-only node, BEP 43: queries are sent with the `ro`
+    /// flag, and incoming queries are ignored.
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Bind the addresses and start serving.  Addresses that cannot be
+    /// bound are skipped with a warning; it is an error if none is.  A
+    /// state file that cannot be loaded is moved aside, and the node
+    /// starts with a fresh identity.
+    pub async fn build(self) -> io::Result<Dht> {
+        let addresses: Vec<String> = if self.addresses.is_empty() {
+            DEFAULT_ADDRESSES.iter().map(|s| s.to_string()).collect()
+        } else {
+            self.addresses
+        };
+
+        let mut sockets = vec![];
+        for address in &addresses {
+            let local = match tokio::net::lookup_host(address).await {
+                Ok(mut addrs) => addrs.next(),
+                Err(_) => None,
+            };
+            // An address may be missing, e.g. the host may have no IPv6.
+            match local {
+                Some(local) => match bind_udp(local) {
+                    Ok(udp) => sockets.push(Arc::new(udp)),
+                    Err(e) => eprintln!("WARNING: cannot bind {}: {}", local, e),
+                },
+                None => eprintln!("WARNING: cannot resolve {}", address),
+            }
+        }
+        let first_ip = match sockets.first() {
+            Some(udp) => udp.local_addr()?.ip(),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrNotAvailable,
+                    "no address is bound",
+                ))
+            }
+        };
+
+        let mut rng = dht::init_chacha();
+        let mut cfg = match &self.state_path {
+            Some(path) => match Config::load(path) {
+                Ok(cfg) => cfg,
+                Err(StateError::Io(e)) if e.kind() == io::ErrorKind::NotFound => {
+                    Config::new(&mut rng, first_ip)
+                }
+                Err(e) => {
+                    eprintln!(
+                        "WARNING: cannot load the state from {}: {}; using a fresh identity",
+                        path.display(),
+                        e
+                    );
+                    keep_bad_state(path);
+                    Config::new(&mut rng, first_ip)
+                }
+            },
+            None => Config::new(&mut rng, first_ip),
+        };
+        // Only the list of the state file is saved back to it.
+        let bootstrap = match self.bootstrap {
+            Some(bootstrap) => bootstrap,
+            None if cfg.peers.is_empty() => self.default_bootstrap,
+            None => cfg.peers.clone(),
+        };
+
+        let (shutdown, _) = watch::channel(false);
+        let queue = Arc::new(QueryQueue::new(self.retry).with_read_only(self.read_only));
+        let mut bound = vec![];
+        for udp in sockets {
+            let addr = udp.local_addr()?;
+            let id = cfg.id_for(addr.ip(), &mut rng);
+            let table = Arc::new(StdMutex::new(RoutingTable::new(id.clone())));
+            bound.push((addr, udp, id, table));
+        }
+        let mut nodes = vec![];
+        for (addr, udp, id, table) in &bound {
+            // The table of the other address family only answers `want`;
+            // it is the one of the node bound to that family, if any.
+            let other = match bound.iter().find(|(a, ..)| a.is_ipv4() != addr.is_ipv4()) {
+                Some((.., other)) => other.clone(),
+                None => Arc::new(StdMutex::new(RoutingTable::new(id.clone()))),
+            };
+            let server = if addr.is_ipv4() {
+                Server::new(id.clone(), table.clone(), other)
+            } else {
+                Server::new(id.clone(), other, table.clone())
+            };
+            let node = Node {
+                addr: *addr,
+                udp: udp.clone(),
+                table: table.clone(),
+                server: Arc::new(server.with_read_only(self.read_only)),
+            };
+            node.spawn(queue.clone(), shutdown.subscribe());
+            nodes.push(node);
+        }
+        cfg.dht_id = nodes[0].id();
+
+        let dht = Dht {
+            inner: Arc::new(Inner {
+                nodes,
+                queue,
+                cfg: StdMutex::new(cfg),
+                state_path: self.state_path,
+                bootstrap,
+                bootstrap_retries: self.bootstrap_retries,
+                bootstrap_backoff: self.bootstrap_backoff,
+                schedule: Default::default(),
+                shutdown,
+            }),
+        };
+        if let Err(e) = dht.save() {
+            eprintln!("WARNING: failed to save the state: {}", e);
+        }
+        dht.spawn_id_watchers();
+        dht.spawn_refresher(REFRESH_CHECK_INTERVAL);
+        if dht.inner.state_path.is_some() {
+            dht.spawn_saver(self.save_interval);
+        }
+        Ok(dht)
+    }
+}
+
+/// Bind a UDP socket.  An IPv6 socket is bound to IPv6 only, so that
+/// `[::]` and `0.0.0.0` can share a port.
+fn bind_udp(local: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(local), Type::DGRAM, Some(Protocol::UDP))?;
+    if local.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&local.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Move the state file that cannot be loaded aside, rather than
+/// overwrite it with the fresh one.
+fn keep_bad_state(path: &Path) {
+    let mut bad = path.as_os_str().to_owned();
+    bad.push(".bad");
+    if std::fs::rename(path, &bad).is_ok() {
+        eprintln!(
+            "WARNING: the old state is kept in {}",
+            bad.to_string_lossy()
+        );
+    }
+}
+
+impl Default for DhtBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A node identity on a single bound address, BEP 45: its own socket,
+/// node id and routing table.
+struct Node {
+    addr: SocketAddr,
+    udp: Arc<UdpSocket>,
+    table: Arc<StdMutex<RoutingTable>>,
+    server: Arc<Server>,
+}
+
+impl Node {
+    fn id(&self) -> DhtId {
+        self.table.lock().unwrap().self_id().clone()
+    }
+
+    /// Serve until shutdown.  The server replies from the socket the
+    /// query arrived on.
+    fn spawn(&self, queue: Arc<QueryQueue>, mut shutdown: watch::Receiver<bool>) {
+        let server = self.server.clone();
+        let udp = self.udp.clone();
+        tokio::task::spawn(async move {
+            tokio::select! {
+                _ = server.run(udp, queue) => {}
+                _ = shutdown.changed() => {}
+            }
+        });
+    }
+}
+
+struct Inner {
+    nodes: Vec<Node>,
+    queue: Arc<QueryQueue>,
+    cfg: StdMutex<Config>,
+    state_path: Option<PathBuf>,
+    /// `host:port` addresses of the nodes to bootstrap from.
+    bootstrap: Vec<String>,
+    bootstrap_retries: u32,
+    bootstrap_backoff: Duration,
+    /// When the nodes may be sampled again, BEP 51.
+    schedule: Arc<StdMutex<lookup::SampleSchedule>>,
+    shutdown: watch::Sender<bool>,
+}
+
+/// Handle to the running node.  Clones refer to the same node.
+#[derive(Clone)]
+pub struct Dht {
+    inner: Arc<Inner>,
+}
+
+impl Dht {
+    /// Node id of the first bound address.
+    pub fn id(&self) -> DhtId {
+        self.primary().id()
+    }
+
+    /// Bound addresses, with their node ids.
+    pub fn local_addrs(&self) -> Vec<(SocketAddr, DhtId)> {
+        self.inner
+            .nodes
+            .iter()
+            .map(|node| (node.addr, node.id()))
+            .collect()
+    }
+
+    /// Our external addresses, as voted by the responders of the
+    /// bound addresses.
+    pub fn external_ips(&self) -> Vec<IpAddr> {
+        let mut ips = vec![];
+        for node in &self.inner.nodes {
+            let (ip, ip6) = node.server.external_ip();
+            for ip in ip.into_iter().chain(ip6) {
+                if !ips.contains(&ip) {
+                    ips.push(ip);
+                }
+            }
+        }
+        ips
+    }
+
+    fn primary(&self) -> &Node {
+        &self.inner.nodes[0]
+    }
+
+    /// Sockets of the bound addresses with their routing tables.
+    fn sockets(&self) -> Vec<(Arc<UdpSocket>, Arc<StdMutex<RoutingTable>>)> {
+        self.inner
+            .nodes
+            .iter()
+            .map(|node| (node.udp.clone(), node.table.clone()))
+            .collect()
+    }
+
+    /// The node of the address family of `addr`, or the primary one.
+    fn node_for(&self, addr: &SocketAddr) -> &Node {
+        self.inner
+            .nodes
+            .iter()
+            .find(|node| node.addr.is_ipv4() == addr.is_ipv4())
+            .unwrap_or_else(|| self.primary())
+    }
+
+    /// Persist id changes made on external address votes.
+    fn spawn_id_watchers(&self) {
+        for node in &self.inner.nodes {
+            let mut id_changes = node.server.subscribe_id();
+            let ip = node.addr.ip();
+            let primary = node.addr == self.primary().addr;
+            let dht = Arc::downgrade(&self.inner);
+            tokio::task::spawn(async move {
+                while id_changes.changed().await.is_ok() {
+                    let id = id_changes.borrow_and_update().clone();
+                    let dht = match dht.upgrade() {
+                        Some(inner) => Dht { inner },
+                        None => return,
+                    };
+                    {
+                        let mut cfg = dht.inner.cfg.lock().unwrap();
+                        if primary {
+                            cfg.dht_id = id.clone();
+                        }
+                        cfg.set_id(ip, id);
+                    }
+                    if let Err(e) = dht.save() {
+                        eprintln!("WARNING: failed to save the state: {}", e);
+                    }
+                }
+            });
+        }
+    }
+
+    /// Save the state periodically until shutdown.
+    fn spawn_saver(&self, interval: Duration) {
+        let dht = Arc::downgrade(&self.inner);
+        let mut shutdown = self.inner.shutdown.subscribe();
+        tokio::task::spawn(async move {
+            let start = tokio::time::Instant::now() + interval;
+            let mut ticks = tokio::time::interval_at(start, interval);
+            loop {
+                tokio::select! {
+                    _ = ticks.tick() => {}
+                    _ = shutdown.changed() => return,
+                }
+                let dht = match dht.upgrade() {
+                    Some(inner) => Dht { inner },
+                    None => return,
+                };
+                if let Err(e) = dht.save() {
+                    eprintln!("WARNING: failed to save the state: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Refresh the stale buckets periodically until shutdown.
+    fn spawn_refresher(&self, interval: Duration) {
+        let dht = Arc::downgrade(&self.inner);
+        let mut shutdown = self.inner.shutdown.subscribe();
+        tokio::task::spawn(async move {
+            let start = tokio::time::Instant::now() + interval;
+            let mut ticks = tokio::time::interval_at(start, interval);
+            loop {
+                tokio::select! {
+                    _ = ticks.tick() => {}
+                    _ = shutdown.changed() => return,
+                }
+                let dht = match dht.upgrade() {
+                    Some(inner) => Dht { inner },
+                    None => return,
+                };
+                dht.refresh().await;
+            }
+        });
+    }
+
+    /// Look up a random id in each bucket that hasn't changed for 15
+    /// minutes, BEP 5.
+    async fn refresh(&self) {
+        let now = Instant::now();
+        let mut rng = dht::init_chacha();
+        let mut lookups = vec![];
+        for node in &self.inner.nodes {
+            let targets: Vec<_> = {
+                let table = node.table.lock().unwrap();
+                table
+                    .stale_buckets(now)
+                    .into_iter()
+                    .map(|idx| table.random_id_in_bucket(idx, &mut rng))
+                    .collect()
+            };
+            lookups.extend(targets.into_iter().map(|target| {
+                lookup::find_node(
+                    self.inner.queue.clone(),
+                    node.udp.clone(),
+                    node.table.clone(),
+                    target,
+                )
+            }));
+        }
+        future::join_all(lookups).await;
+    }
+
+    /// Save the ids and the contacts with the times they were last
+    /// seen.  Saved contacts are kept until the routing tables get
+    /// some.
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.inner.state_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let (now, wall_now) = (Instant::now(), SystemTime::now());
+        let contacts: Vec<_> = self
+            .inner
+            .nodes
+            .iter()
+            .flat_map(|node| node.table.lock().unwrap().seen_contacts(now))
+            .map(|(contact, seen)| (contact, wall_now - now.saturating_duration_since(seen)))
+            .collect();
+        let mut cfg = self.inner.cfg.lock().unwrap();
+        if !contacts.is_empty() {
+            cfg.set_contacts(&contacts);
+        }
+        Ok(cfg.write(path)?)
+    }
+
+    fn good_len(&self) -> usize {
+        let now = Instant::now();
+        self.inner
+            .nodes
+            .iter()
+            .map(|node| node.table.lock().unwrap().good_len(now))
+            .sum()
+    }
+
+    /// Join the network.  The contacts saved in the state file are
+    /// pinged first; while the routing tables have fewer than K good
+    /// nodes, the bootstrap nodes are asked for the nodes closest to
+    /// our own ids too.  Then the ids are looked up to fill the tables.
+    /// It is retried with backoff while the tables are short of good
+    /// nodes.  Returns the number of nodes in the routing tables.
+    pub async fn bootstrap(&self) -> usize {
+        self.ping_saved_contacts().await;
+        let mut backoff = self.inner.bootstrap_backoff;
+        for retry in 0..=self.inner.bootstrap_retries {
+            if retry > 0 {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+            if self.good_len() < K {
+                self.query_bootstrap_nodes().await;
+            }
+            for node in &self.inner.nodes {
+                lookup::find_node(
+                    self.inner.queue.clone(),
+                    node.udp.clone(),
+                    node.table.clone(),
+                    node.id(),
+                )
+                .await;
+            }
+
+            let good = self.good_len();
+            if good >= K {
+                break;
+            }
+            if retry < self.inner.bootstrap_retries {
+                eprintln!(
+                    "WARNING: {} good nodes after bootstrap, retrying in {:?}",
+                    good, backoff
+                );
+            }
+        }
+        self.inner
+            .nodes
+            .iter()
+            .map(|node| node.table.lock().unwrap().len())
+            .sum()
+    }
+
+    async fn ping_saved_contacts(&self) {
+        let contacts = self.inner.cfg.lock().unwrap().contacts();
+        let pings = contacts.iter().filter_map(|(contact, _)| {
+            let node = self.node_for(&contact.addr);
+            if node.addr.is_ipv4() != contact.addr.is_ipv4() {
+                return None;
+            }
+            Some(lookup::ping_query(
+                self.inner.queue.clone(),
+                node.udp.clone(),
+                node.table.clone(),
+                contact.addr,
+                self.inner.queue.retry_policy(),
+            ))
+        });
+        future::join_all(pings).await;
+    }
+
+    async fn query_bootstrap_nodes(&self) {
+        let mut addrs = vec![];
+        for host in &self.inner.bootstrap {
+            match tokio::net::lookup_host(host.as_str()).await {
+                Ok(resolved) => addrs.extend(resolved),
+                Err(e) => eprintln!("WARNING: cannot resolve {}: {}", host, e),
+            }
+        }
+
+        let queries = self.inner.nodes.iter().flat_map(|node| {
+            addrs
+                .iter()
+                .filter(move |addr| addr.is_ipv4() == node.addr.is_ipv4())
+                .map(move |addr| {
+                    lookup::find_node_query(
+                        self.inner.queue.clone(),
+                        node.udp.clone(),
+                        node.table.clone(),
+                        *addr,
+                        node.id(),
+                    )
+                })
+        });
+        future::join_all(queries).await;
+    }
+
+    /// Nodes closest to the target that have responded, up to K of
+    /// each bound address.
+    pub async fn find_node(&self, target: DhtId) -> Vec<Contact> {
+        let lookups = self.inner.nodes.iter().map(|node| {
+            lookup::find_node(
+                self.inner.queue.clone(),
+                node.udp.clone(),
+                node.table.clone(),
+                target.clone(),
+            )
+        });
+        let mut found: Vec<_> = future::join_all(lookups)
+            .await
+            .into_iter()
+            .flatten()
+            .collect();
+        found.sort_by_key(|contact| contact.id.distance(&target));
+        found
+    }
+
+    /// Ask a single node for the nodes it knows closest to the target.
+    pub async fn find_node_at(
+        &self,
+        addr: SocketAddr,
+        target: DhtId,
+    ) -> Result<Vec<Contact>, KrpcError> {
+        let node = self.node_for(&addr);
+        lookup::find_node_query(
+            self.inner.queue.clone(),
+            node.udp.clone(),
+            node.table.clone(),
+            addr,
+            target,
+        )
+        .await
+    }
+
+    /// Nodes of the routing tables of all bound addresses.
+    pub fn contacts(&self) -> Vec<Contact> {
+        self.inner
+            .nodes
+            .iter()
+            .flat_map(|node| {
+                let table = node.table.lock().unwrap();
+                table.contacts().cloned().collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Stream of unique peers of the torrent, looked up from every
+    /// bound address.  The stream ends when the lookups are finished.
+    pub fn get_peers(&self, info_hash: DhtId) -> impl Stream<Item = SocketAddr> {
+        let lookups = self.inner.nodes.iter().map(|node| {
+            Box::pin(lookup::get_peers(
+                self.inner.queue.clone(),
+                node.udp.clone(),
+                node.table.clone(),
+                info_hash.clone(),
+                false,
+            ))
+        });
+        let mut seen = HashSet::new();
+        stream::select_all(lookups).filter(move |peer| future::ready(seen.insert(*peer)))
+    }
+
+    /// Announce us as a peer of the torrent on `port`, or on the source
+    /// port of our packets if `None`.  `seed` tells we have the complete
+    /// torrent.  It is announced from every bound address.  Returns
+    /// the number of nodes that have acknowledged the announce.
+    pub async fn announce(&self, info_hash: DhtId, port: Option<u16>, seed: bool) -> usize {
+        let announces = self.inner.nodes.iter().map(|node| {
+            lookup::announce(
+                self.inner.queue.clone(),
+                node.udp.clone(),
+                node.table.clone(),
+                info_hash.clone(),
+                port.unwrap_or_default(),
+                port.is_none(),
+                seed,
+            )
+        });
+        future::join_all(announces).await.into_iter().sum()
+    }
+
+    /// Stream of unique info hashes sampled from the whole network with
+    /// `sample_infohashes`, BEP 51.  Nodes are sampled no more often
+    /// than they ask for, so a later walk skips the nodes sampled
+    /// recently.  The stream ends when the walks are finished.
+    pub fn sample_infohashes(&self) -> impl Stream<Item = DhtId> {
+        let walks = self.inner.nodes.iter().map(|node| {
+            Box::pin(lookup::sample_infohashes(
+                self.inner.queue.clone(),
+                node.udp.clone(),
+                node.table.clone(),
+                self.inner.schedule.clone(),
+            ))
+        });
+        let mut seen = HashSet::new();
+        stream::select_all(walks)
+            .filter(move |info_hash| future::ready(seen.insert(info_hash.clone())))
+    }
+
+    /// Estimate the number of seeds and downloaders of the torrent,
+    /// BEP 33.
+    pub async fn scrape(&self, info_hash: DhtId) -> ScrapeEstimate {
+        lookup::scrape(self.inner.queue.clone(), &self.sockets(), info_hash).await
+    }
+
+    /// Fetch an immutable item by its target, BEP 44.
+    pub async fn get_immutable(&self, target: DhtId) -> Option<Value> {
+        let lookups = self.inner.nodes.iter().map(|node| {
+            lookup::get_immutable(
+                self.inner.queue.clone(),
+                node.udp.clone(),
+                node.table.clone(),
+                target.clone(),
+            )
+        });
+        future::join_all(lookups).await.into_iter().flatten().next()
+    }
+
+    /// Store an immutable item on the nodes closest to its target, BEP
+    /// 44.  Returns the target and the number of nodes that have stored
+    /// the item.
+    pub async fn put_immutable(&self, v: Value) -> Result<(DhtId, usize), ItemError> {
+        let target = bep_0044::immutable_target(&v);
+        let puts = self.inner.nodes.iter().map(|node| {
+            lookup::put_immutable(
+                self.inner.queue.clone(),
+                node.udp.clone(),
+                node.table.clone(),
+                v.clone(),
+            )
+        });
+        let mut stored = 0;
+        for put in future::join_all(puts).await {
+            stored += put?.1;
+        }
+        Ok((target, stored))
+    }
+
+    /// Public key the mutable items of the node are signed with, kept
+    /// in the state file.
+    pub fn public_key(&self) -> [u8; bep_0044::PUBLIC_KEY_SIZE] {
+        self.inner.cfg.lock().unwrap().keypair().public.to_bytes()
+    }
+
+    /// Fetch the newest version of the mutable item of the public key
+    /// and salt, BEP 44.  Items with invalid signatures are ignored.
+    pub async fn get_mutable(
+        &self,
+        k: [u8; bep_0044::PUBLIC_KEY_SIZE],
+        salt: Vec<u8>,
+    ) -> Option<MutableItem> {
+        let lookups = self.inner.nodes.iter().map(|node| {
+            lookup::get_mutable(
+                self.inner.queue.clone(),
+                node.udp.clone(),
+                node.table.clone(),
+                k,
+                salt.clone(),
+            )
+        });
+        future::join_all(lookups)
+            .await
+            .into_iter()
+            .flatten()
+            .max_by_key(|item| item.seq)
+    }
+
+    /// Sign a new version of our mutable item with the salt and store
+    /// it on the nodes closest to its target, BEP 44.  Returns the item
+    /// and the number of nodes that have stored it.
+    pub async fn put_mutable(
+        &self,
+        salt: Vec<u8>,
+        v: Value,
+    ) -> Result<(MutableItem, usize), ItemError> {
+        let keypair = self.inner.cfg.lock().unwrap().keypair();
+        lookup::put_mutable(self.inner.queue.clone(), &self.sockets(), &keypair, salt, v).await
+    }
+
+    /// Current info hash of the updating torrent of the magnet link,
+    /// BEP 46.
+    pub async fn resolve_magnet(&self, link: &MagnetLink) -> Option<DhtId> {
+        let item = self.get_mutable(link.k, link.salt.clone()).await?;
+        bep_0046::info_hash(&item.v)
+    }
+
+    /// Stream of unique peers of the current version of the updating
+    /// torrent, with its info hash; `None` if the link cannot be
+    /// resolved.
+    pub async fn get_magnet_peers(
+        &self,
+        link: &MagnetLink,
+    ) -> Option<(DhtId, impl Stream<Item = SocketAddr>)> {
+        let info_hash = self.resolve_magnet(link).await?;
+        let peers = self.get_peers(info_hash.clone());
+        Some((info_hash, peers))
+    }
+
+    /// Point the updating torrent of our key and `salt` to the new info
+    /// hash, BEP 46.  Returns the magnet link with the number of nodes
+    /// that have stored the item.
+    pub async fn publish_magnet(
+        &self,
+        salt: Vec<u8>,
+        info_hash: &DhtId,
+    ) -> Result<(MagnetLink, usize), ItemError> {
+        let keypair = self.inner.cfg.lock().unwrap().keypair();
+        lookup::publish_magnet(
+            self.inner.queue.clone(),
+            &self.sockets(),
+            &keypair,
+            salt,
+            info_hash,
+        )
+        .await
+    }
+
+    /// Ping the node, returning its id.
+    pub async fn ping(&self, addr: SocketAddr) -> Result<DhtId, KrpcError> {
+        self.ping_with_retry(addr, self.inner.queue.retry_policy())
+            .await
+    }
+
+    /// Ping the node with its own retry policy, returning its id.
+    pub async fn ping_with_retry(
+        &self,
+        addr: SocketAddr,
+        retry: RetryPolicy,
+    ) -> Result<DhtId, KrpcError> {
+        let node = self.node_for(&addr);
+        lookup::ping_query(
+            self.inner.queue.clone(),
+            node.udp.clone(),
+            node.table.clone(),
+            addr,
+            retry,
+        )
+        .await
+    }
+
+    /// Stop serving and save the state.  Queries issued after the
+    /// shutdown get no replies.
+    pub async fn shutdown(&self) -> io::Result<()> {
+        self.inner.shutdown.send_replace(true);
+        self.save()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn node(name: &str) -> Dht {
+        let state_path =
+            std::env::temp_dir().join(format!("duhast-{}-{}.state", name, std::process::id()));
+        let _ = std::fs::remove_file(&state_path);
+        DhtBuilder::new()
+            .with_bind_address("127.0.0.1:0")
+            .with_state_path(state_path)
+            .with_bootstrap_nodes(Vec::<String>::new())
+            .build()
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_two_nodes() {
+        let server = node("server").await;
+        let (server_addr, server_id) = server.local_addrs()[0].clone();
+        let client = DhtBuilder::new()
+            .with_bind_address("127.0.0.1:0")
+            .with_state_path(
+                std::env::temp_dir().join(format!("duhast-client-{}.state", std::process::id())),
+            )
+            .with_bootstrap_nodes(vec![server_addr.to_string()])
+            .with_bootstrap_retries(0, Duration::ZERO)
+            .build()
+            .await
+            .unwrap();
+
+        assert_eq!(client.bootstrap().await, 1);
+        assert_eq!(client.ping(server_addr).await.unwrap(), server_id);
+
+        let info_hash = DhtId([3; 20]);
+        assert_eq!(
+            client.announce(info_hash.clone(), Some(6881), false).await,
+            1
+        );
+        let estimate = client.scrape(info_hash.clone()).await;
+        assert_eq!(
+            (estimate.seeds.round(), estimate.leechers.round()),
+            (0.0, 1.0)
+        );
+        let peers: Vec<_> = client.get_peers(info_hash.clone()).collect().await;
+        assert_eq!(peers, vec![SocketAddr::from(([127, 0, 0, 1], 6881))]);
+        let samples: Vec<_> = client.sample_infohashes().collect().await;
+        assert_eq!(samples, vec![info_hash]);
+        // The server asked to wait before sampling it again.
+        assert_eq!(client.sample_infohashes().collect::<Vec<_>>().await, vec![]);
+
+        let found = client.find_node(server_id.clone()).await;
+        assert_eq!(found, vec![Contact::new(server_id.clone(), server_addr)]);
+        let known = client.find_node_at(server_addr, server_id.clone()).await;
+        // The server knows only the client.
+        assert_eq!(known.unwrap().len(), 0);
+        assert_eq!(
+            client.contacts(),
+            vec![Contact::new(server_id.clone(), server_addr)]
+        );
+
+        server.shutdown().await.unwrap();
+        assert!(client.ping(server_addr).await.unwrap_err().is_timeout());
+        client.shutdown().await.unwrap();
+        for dht in [server, client].iter() {
+            let _ = std::fs::remove_file(dht.inner.state_path.as_ref().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shared_port() {
+        // The host may have no IPv6.
+        if std::net::UdpSocket::bind("[::]:0").is_err() {
+            return;
+        }
+        let port = std::net::UdpSocket::bind("0.0.0.0:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let dht = DhtBuilder::new()
+            .with_bind_address(format!("0.0.0.0:{}", port))
+            .with_bind_address(format!("[::]:{}", port))
+            .without_state_file()
+            .with_bootstrap_nodes(Vec::<String>::new())
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(dht.local_addrs().len(), 2);
+        dht.shutdown().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_dual_stack() {
+        let dual_stack = |name: &str, bootstrap: Vec<String>| {
+            let state_path =
+                std::env::temp_dir().join(format!("duhast-{}-{}.state", name, std::process::id()));
+            let _ = std::fs::remove_file(&state_path);
+            DhtBuilder::new()
+                .with_bind_address("127.0.0.1:0")
+                .with_bind_address("[::1]:0")
+                .with_state_path(state_path)
+                .with_bootstrap_nodes(bootstrap)
+                .with_bootstrap_retries(0, Duration::ZERO)
+                .build()
+        };
+        let server = dual_stack("dual-server", vec![]).await.unwrap();
+        let server_addrs = server.local_addrs();
+        let client = dual_stack(
+            "dual-client",
+            server_addrs
+                .iter()
+                .map(|(addr, _)| addr.to_string())
+                .collect(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(client.bootstrap().await, 2);
+
+        // Both addresses of the server are found, and are announced to.
+        let mut found = client.find_node(server_addrs[0].1.clone()).await;
+        found.sort_by_key(|contact| contact.addr.is_ipv6());
+        let expected: Vec<_> = server_addrs
+            .iter()
+            .map(|(addr, id)| Contact::new(id.clone(), *addr))
+            .collect();
+        assert_eq!(found, expected);
+        let info_hash = DhtId([4; 20]);
+        assert_eq!(
+            client.announce(info_hash.clone(), Some(6881), false).await,
+            2
+        );
+        let mut peers: Vec<_> = client.get_peers(info_hash).collect().await;
+        peers.sort();
+        assert_eq!(
+            peers,
+            vec![
+                SocketAddr::from(([127, 0, 0, 1], 6881)),
+                SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, 6881)),
+            ]
+        );
+        // One version is stored on the nodes of both families.
+        let (item, stored) = client.put_mutable(vec![], Value::Int(1)).await.unwrap();
+        assert_eq!((item.seq, stored), (1, 2));
+        let (item, stored) = client.put_mutable(vec![], Value::Int(2)).await.unwrap();
+        assert_eq!((item.seq, stored), (2, 2));
+
+        // The IPv4 node answers `want=n6` from the table of the IPv6 one.
+        let (client_addr6, client_id6) = &client.local_addrs()[1];
+        server.ping(*client_addr6).await.unwrap();
+        let udp = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut query = b"d1:ad2:id20:abcdefghij01234567896:target20:".to_vec();
+        query.extend_from_slice(&[0; 20]);
+        query.extend_from_slice(b"4:wantl2:n6ee1:q9:find_node1:t2:aa1:y1:qe");
+        udp.send_to(&query, server_addrs[0].0).await.unwrap();
+        let mut buf = [0; 1500];
+        let len = udp.recv(&mut buf).await.unwrap();
+        let mut compact = client_id6.0.to_vec();
+        compact.extend_from_slice(&std::net::Ipv6Addr::LOCALHOST.octets());
+        compact.extend_from_slice(&client_addr6.port().to_be_bytes());
+        assert!(buf[..len].windows(compact.len()).any(|w| w == &compact[..]));
+
+        for dht in [server, client].iter() {
+            dht.shutdown().await.unwrap();
+            let _ = std::fs::remove_file(dht.inner.state_path.as_ref().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_items() {
+        let server = node("items-server").await;
+        let (server_addr, _) = server.local_addrs()[0].clone();
+        let client = node("items-client").await;
+        client.ping(server_addr).await.unwrap();
+
+        let v = Value::Bytes(b"Hello World!".to_vec());
+        let (target, stored) = client.put_immutable(v.clone()).await.unwrap();
+        assert_eq!(stored, 1);
+        assert_eq!(client.get_immutable(target).await, Some(v));
+        assert_eq!(
+            client
+                .put_immutable(Value::Bytes(vec![0; bep_0044::MAX_VALUE_SIZE]))
+                .await,
+            Err(ItemError::ValueTooBig)
+        );
+
+        let salt = b"foobar".to_vec();
+        for n in 1..=2 {
+            let (item, stored) = client
+                .put_mutable(salt.clone(), Value::Int(n))
+                .await
+                .unwrap();
+            assert_eq!((item.seq, stored), (n, 1));
+        }
+        let item = client.get_mutable(client.public_key(), salt).await.unwrap();
+        assert_eq!((item.seq, item.v), (2, Value::Int(2)));
+        assert_eq!(
+            client
+                .put_mutable(vec![0; bep_0044::MAX_SALT_SIZE + 1], Value::Int(1))
+                .await,
+            Err(ItemError::SaltTooBig)
+        );
+
+        for dht in [server, client].iter() {
+            dht.shutdown().await.unwrap();
+            let _ = std::fs::remove_file(dht.inner.state_path.as_ref().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_magnet() {
+        let server = node("magnet-server").await;
+        let (server_addr, _) = server.local_addrs()[0].clone();
+        let client = node("magnet-client").await;
+        client.ping(server_addr).await.unwrap();
+
+        let salt = b"n".to_vec();
+        let link = MagnetLink::new(client.public_key(), salt.clone());
+        assert_eq!(client.resolve_magnet(&link).await, None);
+        for ih in [DhtId([3; 20]), DhtId([4; 20])].iter() {
+            let (published, stored) = client.publish_magnet(salt.clone(), ih).await.unwrap();
+            assert_eq!((published, stored), (link.clone(), 1));
+        }
+
+        assert_eq!(client.announce(DhtId([4; 20]), Some(6881), false).await, 1);
+        let (info_hash, peers) = client.get_magnet_peers(&link).await.unwrap();
+        assert_eq!(info_hash, DhtId([4; 20]));
+        let peers: Vec<_> = peers.collect().await;
+        assert_eq!(peers, vec![SocketAddr::from(([127, 0, 0, 1], 6881))]);
+
+        for dht in [server, client].iter() {
+            dht.shutdown().await.unwrap();
+            let _ = std::fs::remove_file(dht.inner.state_path.as_ref().unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_default_bootstrap_nodes() {
+        let state_path =
+            std::env::temp_dir().join(format!("duhast-bootstrap-{}.state", std::process::id()));
+        let build = |builder: DhtBuilder| async {
+            let _ = std::fs::remove_file(&state_path);
+            let dht = builder
+                .with_bind_address("127.0.0.1:0")
+                .with_state_path(state_path.clone())
+                .build()
+                .await
+                .unwrap();
+            let bootstrap = dht.inner.bootstrap.clone();
+            dht.shutdown().await.unwrap();
+            // The builder's lists are not saved.
+            assert!(Config::load(&state_path).unwrap().peers.is_empty());
+            bootstrap
+        };
+        // Nothing is hard-coded.
+        assert!(build(DhtBuilder::new()).await.is_empty());
+        let builder = DhtBuilder::new().with_default_bootstrap_nodes(vec!["router:6881"]);
+        assert_eq!(build(builder).await, vec!["router:6881".to_owned()]);
+        let builder = DhtBuilder::new()
+            .with_default_bootstrap_nodes(vec!["router:6881"])
+            .with_bootstrap_nodes(vec!["node:6881"]);
+        assert_eq!(build(builder).await, vec!["node:6881".to_owned()]);
+        let _ = std::fs::remove_file(state_path);
+    }
+
+    #[tokio::test]
+    async fn test_bootstrap_fallback() {
+        let server = node("fallback-server").await;
+        let (server_addr, server_id) = server.local_addrs()[0].clone();
+        let state_path =
+            std::env::temp_dir().join(format!("duhast-fallback-{}.state", std::process::id()));
+        let _ = std::fs::remove_file(&state_path);
+        let client = DhtBuilder::new()
+            .with_bind_address("127.0.0.1:0")
+            .with_state_path(state_path.clone())
+            .with_bootstrap_nodes(vec![format!("localhost:{}", server_addr.port())])
+            .with_bootstrap_retries(1, Duration::from_millis(10))
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(client.bootstrap().await, 1);
+        client.shutdown().await.unwrap();
+
+        // The saved contact is pinged first; nothing listens on the
+        // bootstrap node.
+        let unused = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let client = DhtBuilder::new()
+            .with_bind_address("127.0.0.1:0")
+            .with_state_path(state_path.clone())
+            .with_bootstrap_nodes(vec![unused.local_addr().unwrap().to_string()])
+            .with_bootstrap_retries(0, Duration::ZERO)
+            .with_timeout(Duration::from_millis(100))
+            .build()
+            .await
+            .unwrap();
+        drop(unused);
+        assert_eq!(client.bootstrap().await, 1);
+        assert_eq!(
+            client.contacts(),
+            vec![Contact::new(server_id, server_addr)]
+        );
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+        let _ = std::fs::remove_file(state_path);
+        let _ = std::fs::remove_file(server.inner.state_path.as_ref().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_contacts_saved() {
+        let server = node("saved-server").await;
+        let (server_addr, server_id) = server.local_addrs()[0].clone();
+        let state_path =
+            std::env::temp_dir().join(format!("duhast-saved-{}.state", std::process::id()));
+        let _ = std::fs::remove_file(&state_path);
+        let client = DhtBuilder::new()
+            .with_bind_address("127.0.0.1:0")
+            .with_state_path(state_path.clone())
+            .with_bootstrap_nodes(vec![server_addr.to_string()])
+            .with_bootstrap_retries(0, Duration::ZERO)
+            .with_save_interval(Duration::from_millis(50))
+            .build()
+            .await
+            .unwrap();
+        let before = SystemTime::now() - Duration::from_secs(1);
+        assert_eq!(client.bootstrap().await, 1);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // Saved without a shutdown.
+        let contacts = Config::load(&state_path).unwrap().contacts();
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(contacts[0].0, Contact::new(server_id, server_addr));
+        assert!(contacts[0].1 >= before);
+
+        client.shutdown().await.unwrap();
+        server.shutdown().await.unwrap();
+        let _ = std::fs::remove_file(state_path);
+        let _ = std::fs::remove_file(server.inner.state_path.as_ref().unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_bad_state() {
+        let state_path =
+            std::env::temp_dir().join(format!("duhast-bad-{}.state", std::process::id()));
+        std::fs::write(&state_path, b"garbage").unwrap();
+        let dht = DhtBuilder::new()
+            .with_bind_address("127.0.0.1:0")
+            .with_state_path(state_path.clone())
+            .build()
+            .await
+            .unwrap();
+        let mut bad = state_path.as_os_str().to_owned();
+        bad.push(".bad");
+        assert_eq!(std::fs::read(&bad).unwrap(), b"garbage");
+        assert_eq!(Config::load(&state_path).unwrap().dht_id, dht.id());
+
+        dht.shutdown().await.unwrap();
+        let _ = std::fs::remove_file(state_path);
+        let _ = std::fs::remove_file(bad);
+    }
+
+    #[tokio::test]
+    async fn test_wildcard_id_kept() {
+        let state_path =
+            std::env::temp_dir().join(format!("duhast-wildcard-{}.state", std::process::id()));
+        let _ = std::fs::remove_file(&state_path);
+        let build = || {
+            DhtBuilder::new()
+                .with_bind_address("0.0.0.0:0")
+                .with_state_path(state_path.clone())
+                .with_bootstrap_nodes(Vec::<String>::new())
+                .build()
+        };
+        let dht = build().await.unwrap();
+        let external: IpAddr = [124, 31, 75, 21].into();
+        for n in 1..=3 {
+            dht.inner.nodes[0]
+                .server
+                .vote_external_ip(SocketAddr::from(([8, 8, 8, n], 6881)), external);
+        }
+        assert_eq!(dht.external_ips(), vec![external]);
+        let id = dht.id();
+        assert!(crate::bep_0042::is_valid_id(&id, external));
+        // The watcher records the new id.
+        let bound = dht.local_addrs()[0].0.ip();
+        for _ in 0..100 {
+            if dht
+                .inner
+                .cfg
+                .lock()
+                .unwrap()
+                .id_for(bound, &mut dht::init_chacha())
+                == id
+            {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        dht.shutdown().await.unwrap();
+
+        let dht = build().await.unwrap();
+        assert_eq!(dht.id(), id);
+        dht.shutdown().await.unwrap();
+        let _ = std::fs::remove_file(state_path);
+    }
+
+    #[tokio::test]
+    async fn test_state_kept() {
+        let dht = node("state").await;
+        let id = dht.id();
+        let state_path = dht.inner.state_path.clone().unwrap();
+        dht.shutdown().await.unwrap();
+
+        let dht = DhtBuilder::new()
+            .with_bind_address("127.0.0.1:0")
+            .with_state_path(state_path.clone())
+            .build()
+            .await
+            .unwrap();
+        assert_eq!(dht.id(), id);
+        dht.shutdown().await.unwrap();
+        let _ = std::fs::remove_file(state_path);
+    }
+}
@@ -0,0 +1,328 @@
+//! Storage of peers announced with `announce_peer`.
+use crate::bep_0033::BloomFilter;
+use crate::dht::DhtId;
+use rand::seq::IteratorRandom;
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// Announced peers expire after this period unless re-announced.
+const PEER_TTL: Duration = Duration::from_secs(30 * 60);
+const DEFAULT_MAX_INFO_HASHES: usize = 10_000;
+const DEFAULT_MAX_PEERS_PER_INFO_HASH: usize = 1000;
+/// Number of `values` returned by `get_peers`.  Each IPv4 value takes
+/// 8 bytes, so they fit into a typical 1500 bytes MTU.
+pub(crate) const MAX_VALUES: usize = 100;
+/// The same for IPv6 values taking 21 bytes each.
+pub(crate) const MAX_VALUES6: usize = 50;
+
+struct Peer {
+    port: u16,
+    seen: Instant,
+    seed: bool,
+}
+
+impl Peer {
+    fn is_alive(&self, now: Instant) -> bool {
+        now.saturating_duration_since(self.seen) < PEER_TTL
+    }
+}
+
+pub(crate) struct PeerStore {
+    /// A single entry per IP address, so that a host cannot fill a
+    /// torrent from many ports.
+    torrents: HashMap<DhtId, HashMap<IpAddr, Peer>>,
+    max_info_hashes: usize,
+    max_peers: usize,
+}
+
+impl PeerStore {
+    pub(crate) fn new(max_info_hashes: usize, max_peers: usize) -> Self {
+        Self {
+            torrents: Default::default(),
+            max_info_hashes,
+            max_peers,
+        }
+    }
+
+    /// Store or refresh the peer.  A peer announced again from the same
+    /// IP address replaces the port.  Returns `false` if the store is
+    /// full.
+    pub(crate) fn announce(
+        &mut self,
+        info_hash: DhtId,
+        peer: SocketAddr,
+        seed: bool,
+        now: Instant,
+    ) -> bool {
+        if !self.torrents.contains_key(&info_hash) && self.torrents.len() >= self.max_info_hashes {
+            self.expire(now);
+            if self.torrents.len() >= self.max_info_hashes {
+                return false;
+            }
+        }
+
+        let peers = self.torrents.entry(info_hash).or_default();
+        if !peers.contains_key(&peer.ip()) && peers.len() >= self.max_peers {
+            peers.retain(|_, peer| peer.is_alive(now));
+            if peers.len() >= self.max_peers {
+                return false;
+            }
+        }
+        peers.insert(
+            peer.ip(),
+            Peer {
+                port: peer.port(),
+                seen: now,
+                seed,
+            },
+        );
+        true
+    }
+
+    /// Up to `count` live peers of the torrent of given address
+    /// family, randomly sampled if there are more.  With `noseed`,
+    /// seeds are skipped.
+    pub(crate) fn get_peers<R: Rng>(
+        &mut self,
+        info_hash: &DhtId,
+        ipv6: bool,
+        noseed: bool,
+        count: usize,
+        now: Instant,
+        rng: &mut R,
+    ) -> Vec<SocketAddr> {
+        match self.torrents.get_mut(info_hash) {
+            Some(peers) => {
+                peers.retain(|_, peer| peer.is_alive(now));
+                let sample = peers
+                    .iter()
+                    .filter(|(ip, peer)| ip.is_ipv6() == ipv6 && !(noseed && peer.seed))
+                    .map(|(ip, peer)| SocketAddr::new(*ip, peer.port))
+                    .choose_multiple(rng, count);
+                if peers.is_empty() {
+                    self.torrents.remove(info_hash);
+                }
+                sample
+            }
+            None => vec![],
+        }
+    }
+
+    /// Remove expired peers and empty torrents.
+    pub(crate) fn expire(&mut self, now: Instant) {
+        self.torrents.retain(|_, peers| {
+            peers.retain(|_, peer| peer.is_alive(now));
+            !peers.is_empty()
+        });
+    }
+
+    /// Bloom filters of seeds and downloaders of the torrent, BEP 33;
+    /// `None` if no live peers are known.
+    pub(crate) fn scrape(
+        &mut self,
+        info_hash: &DhtId,
+        now: Instant,
+    ) -> Option<(BloomFilter, BloomFilter)> {
+        let peers = self.torrents.get_mut(info_hash)?;
+        peers.retain(|_, peer| peer.is_alive(now));
+        if peers.is_empty() {
+            self.torrents.remove(info_hash);
+            return None;
+        }
+        let mut seeds = BloomFilter::default();
+        let mut downloaders = BloomFilter::default();
+        for (ip, peer) in peers.iter() {
+            if peer.seed {
+                seeds.insert(*ip);
+            } else {
+                downloaders.insert(*ip);
+            }
+        }
+        Some((seeds, downloaders))
+    }
+
+    /// Up to `count` info hashes with live peers, randomly sampled if
+    /// there are more.
+    pub(crate) fn sample_info_hashes<R: Rng>(
+        &mut self,
+        count: usize,
+        now: Instant,
+        rng: &mut R,
+    ) -> Vec<DhtId> {
+        self.expire(now);
+        self.torrents.keys().cloned().choose_multiple(rng, count)
+    }
+
+    pub(crate) fn info_hashes_count(&self) -> usize {
+        self.torrents.len()
+    }
+}
+
+impl Default for PeerStore {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_INFO_HASHES, DEFAULT_MAX_PEERS_PER_INFO_HASH)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn peer(n: u8) -> SocketAddr {
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, n), 6881).into()
+    }
+
+    fn info_hash(b: u8) -> DhtId {
+        DhtId([b; 20])
+    }
+
+    #[test]
+    fn test_announce_get() {
+        let now = Instant::now();
+        let mut rng = rand::thread_rng();
+        let mut store = PeerStore::default();
+
+        assert!(store.announce(info_hash(1), peer(1), false, now));
+        assert!(store.announce(info_hash(1), peer(2), false, now));
+        assert!(store.announce(info_hash(1), peer(2), false, now));
+
+        let mut peers = store.get_peers(&info_hash(1), false, false, MAX_VALUES, now, &mut rng);
+        peers.sort();
+        assert_eq!(peers, vec![peer(1), peer(2)]);
+        assert!(store
+            .get_peers(&info_hash(2), false, false, MAX_VALUES, now, &mut rng)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_expiry() {
+        let now = Instant::now();
+        let mut rng = rand::thread_rng();
+        let mut store = PeerStore::default();
+
+        store.announce(info_hash(1), peer(1), false, now);
+        store.announce(info_hash(1), peer(2), false, now + PEER_TTL / 2);
+
+        let peers = store.get_peers(
+            &info_hash(1),
+            false,
+            false,
+            MAX_VALUES,
+            now + PEER_TTL,
+            &mut rng,
+        );
+        assert_eq!(peers, vec![peer(2)]);
+
+        store.expire(now + 2 * PEER_TTL);
+        assert_eq!(store.info_hashes_count(), 0);
+    }
+
+    #[test]
+    fn test_limits() {
+        let now = Instant::now();
+        let mut store = PeerStore::new(2, 2);
+
+        assert!(store.announce(info_hash(1), peer(1), false, now));
+        assert!(store.announce(info_hash(1), peer(2), false, now));
+        assert!(!store.announce(info_hash(1), peer(3), false, now));
+        // Re-announce is always accepted.
+        assert!(store.announce(info_hash(1), peer(1), false, now));
+
+        assert!(store.announce(info_hash(2), peer(1), false, now));
+        assert!(!store.announce(info_hash(3), peer(1), false, now));
+        // Expired torrents free space.
+        assert!(store.announce(info_hash(3), peer(1), false, now + PEER_TTL));
+    }
+
+    #[test]
+    fn test_same_ip() {
+        let now = Instant::now();
+        let mut rng = rand::thread_rng();
+        let mut store = PeerStore::new(1, 2);
+        let port = |port| SocketAddr::from((Ipv4Addr::new(10, 0, 0, 1), port));
+
+        for p in 1..=5 {
+            assert!(store.announce(info_hash(1), port(p), false, now));
+        }
+        let peers = store.get_peers(&info_hash(1), false, false, MAX_VALUES, now, &mut rng);
+        assert_eq!(peers, vec![port(5)]);
+        // Another host still fits.
+        assert!(store.announce(info_hash(1), peer(2), false, now));
+    }
+
+    #[test]
+    fn test_sample() {
+        let now = Instant::now();
+        let mut rng = rand::thread_rng();
+        let mut store = PeerStore::default();
+
+        for n in 0..200 {
+            store.announce(info_hash(1), peer(n), false, now);
+        }
+        let peers = store.get_peers(&info_hash(1), false, false, MAX_VALUES, now, &mut rng);
+        assert_eq!(peers.len(), MAX_VALUES);
+    }
+
+    #[test]
+    fn test_sample_info_hashes() {
+        let now = Instant::now();
+        let mut rng = rand::thread_rng();
+        let mut store = PeerStore::default();
+
+        for b in 0..10 {
+            store.announce(info_hash(b), peer(1), false, now);
+        }
+        store.announce(info_hash(10), peer(1), false, now + PEER_TTL / 2);
+
+        let mut sample = store.sample_info_hashes(100, now + PEER_TTL, &mut rng);
+        assert_eq!(sample, vec![info_hash(10)]);
+
+        for b in 0..10 {
+            store.announce(info_hash(b), peer(1), false, now + PEER_TTL);
+        }
+        sample = store.sample_info_hashes(5, now + PEER_TTL, &mut rng);
+        sample.sort();
+        sample.dedup();
+        assert_eq!(sample.len(), 5);
+    }
+
+    #[test]
+    fn test_seeds() {
+        let now = Instant::now();
+        let mut rng = rand::thread_rng();
+        let mut store = PeerStore::default();
+        let peer6 = SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, 1));
+
+        store.announce(info_hash(1), peer(1), true, now);
+        store.announce(info_hash(1), peer(2), false, now);
+        store.announce(info_hash(1), peer6, true, now);
+
+        let peers = store.get_peers(&info_hash(1), false, true, MAX_VALUES, now, &mut rng);
+        assert_eq!(peers, vec![peer(2)]);
+
+        let (seeds, downloaders) = store.scrape(&info_hash(1), now).unwrap();
+        assert_eq!(seeds.estimate().round(), 2.0);
+        assert_eq!(downloaders.estimate().round(), 1.0);
+        assert!(store.scrape(&info_hash(1), now + PEER_TTL).is_none());
+        assert!(store.scrape(&info_hash(2), now).is_none());
+    }
+
+    #[test]
+    fn test_address_family() {
+        let now = Instant::now();
+        let mut rng = rand::thread_rng();
+        let mut store = PeerStore::default();
+        let peer6: SocketAddr = (std::net::Ipv6Addr::LOCALHOST, 1).into();
+
+        store.announce(info_hash(1), peer(1), false, now);
+        store.announce(info_hash(1), peer6, false, now);
+
+        let peers = store.get_peers(&info_hash(1), false, false, MAX_VALUES, now, &mut rng);
+        assert_eq!(peers, vec![peer(1)]);
+        let peers = store.get_peers(&info_hash(1), true, false, MAX_VALUES6, now, &mut rng);
+        assert_eq!(peers, vec![peer6]);
+    }
+}
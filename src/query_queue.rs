@@ -1,7 +1,11 @@
-use crate::dht;
-use serde::Serialize;
+use crate::bencode;
+use crate::dht::{self, QueryKind};
+use serde::Deserialize;
 use std::borrow::Cow;
 use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::fmt;
+use std::io;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::sync::Mutex as StdMutex;
@@ -9,12 +13,222 @@ use std::time::Duration;
 use tokio::net::UdpSocket;
 use tokio::sync::oneshot;
 
-struct TimeoutError {}
-
 pub(crate) type QueryId = u16;
 
+/// How a query is retransmitted when no reply comes in time.  Every
+/// attempt resends the same packet with the same transaction id, and
+/// a reply to any of them resolves the query.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Number of times the query is sent, at least one.
+    attempts: u32,
+    /// Time to wait for a reply to the first attempt.
+    timeout: Duration,
+    /// The timeout is multiplied by it after each attempt, at least one.
+    backoff: f64,
+}
+
+impl RetryPolicy {
+    /// Send the query up to `attempts` times, waiting `timeout` for
+    /// the first reply and `backoff` times longer after each attempt.
+    pub fn new(attempts: u32, timeout: Duration, backoff: f64) -> Result<Self, RetryPolicyError> {
+        if attempts == 0 {
+            return Err(RetryPolicyError::NoAttempts);
+        }
+        // Also rejects NaN.
+        if !(backoff >= 1.0 && backoff.is_finite()) {
+            return Err(RetryPolicyError::Backoff);
+        }
+        Ok(Self {
+            attempts,
+            timeout,
+            backoff,
+        })
+    }
+
+    /// Send the query only once.
+    pub fn once(timeout: Duration) -> Self {
+        Self {
+            attempts: 1,
+            timeout,
+            backoff: 1.0,
+        }
+    }
+
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn backoff(&self) -> f64 {
+        self.backoff
+    }
+
+    pub(crate) fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Time to wait after the attempt waiting `timeout`.  It saturates
+    /// rather than overflows.
+    fn next_timeout(&self, timeout: Duration) -> Duration {
+        Duration::try_from_secs_f64(timeout.as_secs_f64() * self.backoff).unwrap_or(Duration::MAX)
+    }
+}
+
+impl Default for RetryPolicy {
+    /// Two attempts, waiting 1 s and then 2 s.
+    fn default() -> Self {
+        Self {
+            attempts: 2,
+            timeout: Duration::from_secs(1),
+            backoff: 2.0,
+        }
+    }
+}
+
+/// Invalid parameters of a retry policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RetryPolicyError {
+    /// The query is never sent.
+    NoAttempts,
+    /// The backoff is less than one or not finite.
+    Backoff,
+}
+
+impl fmt::Display for RetryPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RetryPolicyError::NoAttempts => write!(f, "expecting at least one attempt"),
+            RetryPolicyError::Backoff => write!(f, "expecting a finite backoff of at least 1"),
+        }
+    }
+}
+
+impl std::error::Error for RetryPolicyError {}
+
+/// Failure of a KRPC query.
+#[derive(Debug)]
+pub enum KrpcError {
+    /// No reply in time.  It is worth retrying, unlike the others.
+    Timeout,
+    /// The query cannot be sent.
+    Send(io::Error),
+    /// The query cannot be encoded.
+    Encode(serde_bencoded::SerError),
+    /// The reply is not the expected response.
+    MalformedReply,
+    /// The node has replied with an error, BEP 5: 201 generic error,
+    /// 202 server error, 203 protocol error, or 204 method unknown.
+    Remote { code: u32, message: String },
+}
+
+impl KrpcError {
+    pub fn is_timeout(&self) -> bool {
+        matches!(self, KrpcError::Timeout)
+    }
+}
+
+impl fmt::Display for KrpcError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KrpcError::Timeout => write!(f, "no reply"),
+            KrpcError::Send(e) => write!(f, "cannot send query: {}", e),
+            KrpcError::Encode(e) => write!(f, "cannot encode query: {}", e),
+            KrpcError::MalformedReply => write!(f, "malformed reply"),
+            KrpcError::Remote { code, message } => write!(f, "error {}: {}", code, message),
+        }
+    }
+}
+
+impl std::error::Error for KrpcError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KrpcError::Send(e) => Some(e),
+            KrpcError::Encode(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Response to a query, of the type the query method implies.
+#[derive(Debug)]
+pub(crate) enum Response {
+    Ping(dht::PingResponse),
+    FindNode(dht::FindNodeResponse<'static>),
+    /// Boxed for the bloom filters of scrapes.
+    GetPeers(Box<dht::GetPeersResponse<'static>>),
+    AnnouncePeer(dht::AnnouncePeerResponse),
+    SampleInfohashes(dht::SampleInfohashesResponse<'static>),
+    Get(dht::GetResponse<'static>),
+    Put(dht::PutResponse),
+}
+
+impl Response {
+    /// Decode the reply to a query of the kind: the response, or the
+    /// KRPC error the node has replied with.  The responder's id is
+    /// checked to be 20 bytes long.
+    pub(crate) fn decode(kind: QueryKind, data: &[u8]) -> Result<Self, KrpcError> {
+        Ok(match kind {
+            QueryKind::Ping => Response::Ping(decode_reply(data)?),
+            QueryKind::FindNode => {
+                Response::FindNode(decode_reply::<dht::FindNodeResponse>(data)?.into_owned())
+            }
+            QueryKind::GetPeers => Response::GetPeers(Box::new(
+                decode_reply::<dht::GetPeersResponse>(data)?.into_owned(),
+            )),
+            QueryKind::AnnouncePeer => Response::AnnouncePeer(decode_reply(data)?),
+            QueryKind::SampleInfohashes => Response::SampleInfohashes(
+                decode_reply::<dht::SampleInfohashesResponse>(data)?.into_owned(),
+            ),
+            QueryKind::Get => Response::Get(decode_reply::<dht::GetResponse>(data)?.into_owned()),
+            QueryKind::Put => Response::Put(decode_reply(data)?),
+        })
+    }
+}
+
+fn decode_reply<'de, R: Deserialize<'de>>(data: &'de [u8]) -> Result<R, KrpcError> {
+    match serde_bencoded::from_bytes_auto::<dht::Message<R>>(data) {
+        Ok(dht::Message::R { r }) => Ok(r),
+        Ok(dht::Message::E { e: (code, message) }) => Err(KrpcError::Remote { code, message }),
+        _ => Err(KrpcError::MalformedReply),
+    }
+}
+
+// Responses are decoded by the query kind, so the conversion to the
+// type of the query only fails on a bug.
+macro_rules! response_try_from {
+    ($variant:ident, $type:ty) => {
+        response_try_from!($variant, $type, |r| r);
+    };
+    ($variant:ident, $type:ty, $unwrap:expr) => {
+        impl TryFrom<Response> for $type {
+            type Error = KrpcError;
+
+            fn try_from(response: Response) -> Result<Self, KrpcError> {
+                match response {
+                    Response::$variant(r) => Ok($unwrap(r)),
+                    _ => Err(KrpcError::MalformedReply),
+                }
+            }
+        }
+    };
+}
+
+response_try_from!(Ping, dht::PingResponse);
+response_try_from!(FindNode, dht::FindNodeResponse<'static>);
+response_try_from!(GetPeers, dht::GetPeersResponse<'static>, |r: Box<_>| *r);
+response_try_from!(AnnouncePeer, dht::AnnouncePeerResponse);
+response_try_from!(SampleInfohashes, dht::SampleInfohashesResponse<'static>);
+response_try_from!(Get, dht::GetResponse<'static>);
+response_try_from!(Put, dht::PutResponse);
+
 struct ReplyInfo {
-    send: oneshot::Sender<Vec<u8>>,
+    kind: QueryKind,
+    send: oneshot::Sender<Result<Response, KrpcError>>,
 }
 
 /// Each node (ip + port combination) has its own queue.
@@ -36,22 +250,25 @@ impl NodeQueue {
 
     #[inline]
     pub fn get_next_id(&mut self) -> QueryId {
-        self.id += 1;
+        self.id = self.id.wrapping_add(1);
         self.id
     }
 
-    pub fn add_reply_info(&mut self, id: QueryId, send: oneshot::Sender<Vec<u8>>) {
+    fn add_reply_info(
+        &mut self,
+        id: QueryId,
+        kind: QueryKind,
+        send: oneshot::Sender<Result<Response, KrpcError>>,
+    ) {
         // TODO we are hiding here a previous reply if it still
         // exists.  Misconfigured instances may get misrouted
         // messages.
-        self.waiting_for_reply.insert(id, ReplyInfo { send });
+        self.waiting_for_reply.insert(id, ReplyInfo { kind, send });
     }
 
-    pub fn got_reply(&mut self, id: QueryId, packet: Vec<u8>) {
-        if let Some((_, info)) = self.waiting_for_reply.remove_entry(&id) {
-            // If receiver doesn't exist anymore, not problem at all.
-            let _ = info.send.send(packet).unwrap();
-        }
+    /// Take the query with the id that waits for a reply, if any.
+    fn got_reply(&mut self, id: QueryId) -> Option<ReplyInfo> {
+        self.waiting_for_reply.remove(&id)
     }
 
     pub fn remove(&mut self, id: QueryId) {
@@ -66,26 +283,70 @@ impl Default for NodeQueue {
 }
 
 pub struct QueryQueue {
-    timeout: Duration,
+    /// Policy of the queries that don't have their own.
+    retry: RetryPolicy,
+    /// Queries are sent with the BEP 43 read-only flag.
+    read_only: bool,
     // A std mutex can be used instead.
     nodes: StdMutex<HashMap<SocketAddr, NodeQueue>>,
 }
 
 impl QueryQueue {
-    pub fn new(timeout: Duration) -> Self {
+    pub fn new(retry: RetryPolicy) -> Self {
         Self {
-            timeout,
+            retry,
+            read_only: false,
             nodes: StdMutex::new(Default::default()),
         }
     }
 
-    pub(crate) async fn send_message<R: Serialize>(
+    pub(crate) fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub(crate) fn retry_policy(&self) -> RetryPolicy {
+        self.retry
+    }
+
+    /// Send the query and wait for the response of its type.
+    pub(crate) async fn query<R>(
+        self: Arc<Self>,
+        udp: Arc<UdpSocket>,
+        sock_addr: SocketAddr,
+        query: dht::Query<'static>,
+    ) -> Result<R, KrpcError>
+    where
+        R: TryFrom<Response, Error = KrpcError>,
+    {
+        let retry = self.retry;
+        self.query_with(udp, sock_addr, query, retry).await
+    }
+
+    /// Send the query with its own retry policy.
+    pub(crate) async fn query_with<R>(
+        self: Arc<Self>,
+        udp: Arc<UdpSocket>,
+        sock_addr: SocketAddr,
+        query: dht::Query<'static>,
+        retry: RetryPolicy,
+    ) -> Result<R, KrpcError>
+    where
+        R: TryFrom<Response, Error = KrpcError>,
+    {
+        self.send_query(udp, sock_addr, query, retry)
+            .await?
+            .try_into()
+    }
+
+    async fn send_query(
         self: Arc<Self>,
         udp: Arc<UdpSocket>,
         sock_addr: SocketAddr,
-        msg: dht::Message<'static, R>,
-    ) -> Result<Vec<u8>, ()> {
-        let (send, recv) = oneshot::channel();
+        query: dht::Query<'static>,
+        retry: RetryPolicy,
+    ) -> Result<Response, KrpcError> {
+        let (send, mut recv) = oneshot::channel();
         let id = {
             // expect is reasonable here because if nodes lock is poisoned,
             // we can only crash.
@@ -95,36 +356,43 @@ impl QueryQueue {
             node_queue.get_next_id()
         };
         let id_bytes = id.to_be_bytes();
+        let kind = query.kind();
 
         let out_msg = dht::OutgoingMessage {
             t: Cow::Borrowed(&id_bytes),
-            msg,
+            ip: None,
+            ro: if self.read_only { Some(true) } else { None },
+            msg: dht::Message::<()>::Q(query),
         };
 
-        if let Ok(buf) = serde_bencoded::to_vec(&out_msg) {
-            udp.send_to(&buf, sock_addr).await.map_err(|_| ())?;
-
-            {
-                let mut guard = self.nodes.lock().expect("cannot handle poinsoned lock");
-                let node_queue = guard.entry(sock_addr).or_default();
-                node_queue.add_reply_info(id, send);
+        let buf = bencode::to_vec(&out_msg).map_err(KrpcError::Encode)?;
+        // Wait for the reply before sending: on a fast link it may
+        // arrive before send_to returns.
+        {
+            let mut guard = self.nodes.lock().expect("cannot handle poinsoned lock");
+            let node_queue = guard.entry(sock_addr).or_default();
+            node_queue.add_reply_info(id, kind, send);
+        }
+        let mut timeout = retry.timeout;
+        for _ in 0..retry.attempts {
+            if let Err(e) = udp.send_to(&buf, sock_addr).await {
+                self.query_expired(sock_addr, id);
+                return Err(KrpcError::Send(e));
             }
 
-            let timeout = self.timeout;
-
             tokio::select! {
-                res = recv => {
-                    res.map_err(|_| ())
+                res = &mut recv => {
+                    // The sender is dropped if the id is reused for
+                    // another query: no reply is coming.
+                    return res.unwrap_or(Err(KrpcError::Timeout));
                 }
                 _ = tokio::time::sleep(timeout) => {
-                    // clear
-                    self.query_expired(sock_addr, id);
-                    Err(())
+                    timeout = retry.next_timeout(timeout);
                 }
             }
-        } else {
-            Err(())
         }
+        self.query_expired(sock_addr, id);
+        Err(KrpcError::Timeout)
     }
 
     fn query_expired(&self, addr: SocketAddr, id: QueryId) {
@@ -134,21 +402,193 @@ impl QueryQueue {
         }
     }
 
-    // It handles only normal replies and error replies.
-    pub(crate) fn got_reply(&self, sock_addr: SocketAddr, id: QueryId, packet: Vec<u8>) {
+    // It handles only normal replies and error replies.  Returns
+    // whether the reply matches a query sent to the address.
+    pub(crate) fn got_reply(&self, sock_addr: SocketAddr, id: QueryId, packet: &[u8]) -> bool {
         let mut guard = self.nodes.lock().expect("cannot handle poinsoned lock");
-        if let Some(node_info) = guard.get_mut(&sock_addr) {
-            node_info.got_reply(id, packet)
+        let info = if let Some(node_info) = guard.get_mut(&sock_addr) {
+            node_info.got_reply(id)
         } else {
             // TODO logging
             eprintln!("WARNING: Not found node info for {}", sock_addr);
+            None
+        };
+        // The reply is decoded without holding the lock of all nodes.
+        drop(guard);
+        match info {
+            Some(info) => {
+                // If receiver doesn't exist anymore, not problem at all.
+                let _ = info.send.send(Response::decode(info.kind, packet));
+                true
+            }
+            None => false,
         }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_response() {
+        const FIND_NODE: &[u8] =
+            b"d1:rd2:id20:0123456789abcdefghij5:nodes26:mnopqrstuvwxyz123456axje.ue1:t2:aa1:y1:re";
+        let r: dht::FindNodeResponse = Response::decode(QueryKind::FindNode, FIND_NODE)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        assert_eq!(r.id, dht::DhtId(*b"0123456789abcdefghij"));
+        assert_eq!(r.nodes.unwrap().iter().count(), 1);
+        // The reply doesn't have the token of a get_peers response.
+        assert!(matches!(
+            Response::decode(QueryKind::GetPeers, FIND_NODE),
+            Err(KrpcError::MalformedReply)
+        ));
+        let r: Result<dht::PingResponse, _> = Response::decode(QueryKind::FindNode, FIND_NODE)
+            .unwrap()
+            .try_into();
+        assert!(matches!(r, Err(KrpcError::MalformedReply)));
+
+        match Response::decode(
+            QueryKind::Ping,
+            b"d1:eli201e23:A Generic Error Ocurrede1:t2:aa1:y1:ee",
+        ) {
+            Err(KrpcError::Remote { code, message }) => {
+                assert_eq!(code, dht::GENERIC_ERROR);
+                assert_eq!(message, "A Generic Error Ocurred");
+            }
+            _ => panic!("expecting remote error"),
+        }
+        assert!(matches!(
+            Response::decode(QueryKind::Ping, b"d1:rde1:t2:aa1:y1:re"),
+            Err(KrpcError::MalformedReply)
+        ));
+        // The responder's id must be 20 bytes.
+        assert!(matches!(
+            Response::decode(
+                QueryKind::Ping,
+                b"d1:rd2:id19:mnopqrstuvwxyz12345e1:t2:aa1:y1:re"
+            ),
+            Err(KrpcError::MalformedReply)
+        ));
+    }
+
+    /// A peer that ignores the first `lost` packets and answers the
+    /// rest with a ping response.  Returns its address and the packets
+    /// it got.
+    async fn lossy_peer(lost: usize) -> (SocketAddr, Arc<StdMutex<Vec<Vec<u8>>>>) {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = udp.local_addr().unwrap();
+        let packets = Arc::new(StdMutex::new(vec![]));
+        let received = packets.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            while let Ok((len, from)) = udp.recv_from(&mut buf).await {
+                let count = {
+                    let mut received = received.lock().unwrap();
+                    received.push(buf[..len].to_vec());
+                    received.len()
+                };
+                if count > lost {
+                    let msg: dht::IncomingMessage =
+                        serde_bencoded::from_bytes_auto(&buf[..len]).unwrap();
+                    let mut reply = b"d1:rd2:id20:0123456789abcdefghije1:t2:".to_vec();
+                    reply.extend_from_slice(msg.t);
+                    reply.extend_from_slice(b"1:y1:re");
+                    udp.send_to(&reply, from).await.unwrap();
+                }
+            }
+        });
+        (addr, packets)
+    }
+
+    /// A socket that passes the replies it gets to the queue.
+    async fn client(queue: Arc<QueryQueue>) -> Arc<UdpSocket> {
+        let udp = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let socket = udp.clone();
+        tokio::spawn(async move {
+            let mut buf = [0; 1500];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let msg: dht::IncomingMessage =
+                    serde_bencoded::from_bytes_auto(&buf[..len]).unwrap();
+                if let [a, b] = *msg.t {
+                    queue.got_reply(from, QueryId::from_be_bytes([a, b]), &buf[..len]);
+                }
+            }
+        });
+        udp
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let second = Duration::from_secs(1);
+        assert_eq!(
+            RetryPolicy::new(0, second, 2.0),
+            Err(RetryPolicyError::NoAttempts)
+        );
+        for backoff in [0.5, -1.0, f64::NAN, f64::INFINITY].iter() {
+            assert_eq!(
+                RetryPolicy::new(2, second, *backoff),
+                Err(RetryPolicyError::Backoff)
+            );
+        }
+        let retry = RetryPolicy::new(2, Duration::MAX, 2.0).unwrap();
+        assert_eq!(retry.next_timeout(second), 2 * second);
+        assert_eq!(retry.next_timeout(Duration::MAX), Duration::MAX);
+    }
+
+    fn ping() -> dht::Query<'static> {
+        dht::Query::Ping(dht::PingQuery {
+            id: dht::DhtId([1; 20]),
+        })
+    }
+
+    #[tokio::test]
+    async fn test_retransmit() {
+        let queue = Arc::new(QueryQueue::new(
+            RetryPolicy::new(3, Duration::from_millis(50), 2.0).unwrap(),
+        ));
+        let udp = client(queue.clone()).await;
+
+        let (addr, packets) = lossy_peer(1).await;
+        let r: dht::PingResponse = queue
+            .clone()
+            .query(udp.clone(), addr, ping())
+            .await
+            .unwrap();
+        assert_eq!(r.id, dht::DhtId(*b"0123456789abcdefghij"));
+        {
+            // The retransmission is the same packet.
+            let packets = packets.lock().unwrap();
+            assert_eq!(packets.len(), 2);
+            assert_eq!(packets[0], packets[1]);
+        }
+
+        // The policy of the call replaces the queue's one.
+        let (addr, packets) = lossy_peer(1).await;
+        let r: Result<dht::PingResponse, _> = queue
+            .clone()
+            .query_with(
+                udp.clone(),
+                addr,
+                ping(),
+                RetryPolicy::once(Duration::from_millis(50)),
+            )
+            .await;
+        assert!(r.unwrap_err().is_timeout());
+        assert_eq!(packets.lock().unwrap().len(), 1);
 
-    pub(crate) async fn declare_dead(&self, sock_addr: SocketAddr) {
-        self.nodes
-            .lock()
-            .expect("cannot handle poinsoned lock")
-            .remove(&sock_addr);
+        let (addr, packets) = lossy_peer(usize::MAX).await;
+        let start = tokio::time::Instant::now();
+        let r: Result<dht::PingResponse, _> = queue.clone().query(udp, addr, ping()).await;
+        assert!(r.unwrap_err().is_timeout());
+        // 50 ms, 100 ms and 200 ms.
+        assert!(start.elapsed() >= Duration::from_millis(350));
+        assert_eq!(packets.lock().unwrap().len(), 3);
+        // The reply slot is released.
+        assert!(queue.nodes.lock().unwrap()[&addr]
+            .waiting_for_reply
+            .is_empty());
     }
 }
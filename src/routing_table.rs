@@ -0,0 +1,661 @@
+//! Kademlia routing table, as described in
+//! https://www.bittorrent.org/beps/bep_0005.html
+//!
+//! Unlike BEP 5, which splits buckets lazily, the table has a fixed
+//! set of 160 buckets: the bucket `i` holds nodes whose id shares
+//! exactly `i` leading bits with our own id.
+//!
+//! Node ids are checked against their addresses as BEP 42 describes;
+//! see `IdPolicy` for how violations are treated.
+use crate::bep_0042::{self, IdPolicy};
+use crate::dht::{DhtId, DHT_ID_BYTE_SIZE};
+use rand::{CryptoRng, Rng};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Bucket capacity.
+pub(crate) const K: usize = 8;
+const BUCKETS_NUM: usize = DHT_ID_BYTE_SIZE * 8;
+/// After this period of inactivity a good node becomes questionable.
+/// It is also the bucket refresh interval.
+const GOOD_NODE_PERIOD: Duration = Duration::from_secs(15 * 60);
+/// A node failed to respond to this number of queries in a row is
+/// bad.
+const MAX_FAILED_QUERIES: u32 = 3;
+/// A questionable node is asked to be pinged at most once in this
+/// period, however many nodes wait for its place.
+const PING_PERIOD: Duration = Duration::from_secs(60);
+
+/// Node id and its address.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Contact {
+    pub id: DhtId,
+    pub addr: SocketAddr,
+}
+
+impl Contact {
+    pub(crate) fn new(id: DhtId, addr: SocketAddr) -> Self {
+        Self { id, addr }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum NodeStatus {
+    Good,
+    Questionable,
+    Bad,
+}
+
+struct Node {
+    contact: Contact,
+    /// Last time the node has responded to our query.
+    last_reply: Option<Instant>,
+    /// Last time the node has sent us a query.
+    last_query: Option<Instant>,
+    failed_queries: u32,
+    /// Last time the node was asked to be pinged for its place.
+    pinged: Option<Instant>,
+    /// The id conforms to BEP 42.
+    valid_id: bool,
+}
+
+impl Node {
+    fn new(contact: Contact) -> Self {
+        Self {
+            valid_id: bep_0042::is_valid_id(&contact.id, contact.addr.ip()),
+            contact,
+            last_reply: None,
+            last_query: None,
+            failed_queries: 0,
+            pinged: None,
+        }
+    }
+
+    fn last_seen(&self) -> Option<Instant> {
+        self.last_reply.max(self.last_query)
+    }
+
+    fn status(&self, now: Instant) -> NodeStatus {
+        if self.failed_queries >= MAX_FAILED_QUERIES {
+            return NodeStatus::Bad;
+        }
+        // A node is good only if it has ever responded to us.
+        match (self.last_reply, self.last_seen()) {
+            (Some(_), Some(seen)) if now.saturating_duration_since(seen) < GOOD_NODE_PERIOD => {
+                NodeStatus::Good
+            }
+            _ => NodeStatus::Questionable,
+        }
+    }
+}
+
+struct Bucket {
+    nodes: Vec<Node>,
+    last_changed: Instant,
+}
+
+impl Bucket {
+    fn new(now: Instant) -> Self {
+        Self {
+            nodes: Vec::with_capacity(K),
+            last_changed: now,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum InsertOutcome {
+    /// The node is added to the table.
+    Added,
+    /// The node is already known; its activity is recorded.
+    Updated,
+    /// A bad node, or a node with an invalid id, is evicted to give
+    /// place for the new one.
+    Replaced(Contact),
+    /// The bucket is full, but it has a questionable node.  The caller
+    /// should ping it, and replace it with the new node if it fails to
+    /// respond.  The node is not offered again for a while.
+    PingQuestionable(Contact),
+    /// The bucket is full of good nodes; the node is discarded.
+    Discarded,
+    /// The node has our id, a known id with a different address, or
+    /// an invalid id the policy doesn't allow.
+    Rejected,
+}
+
+pub(crate) struct RoutingTable {
+    self_id: DhtId,
+    buckets: Vec<Bucket>,
+    id_policy: IdPolicy,
+}
+
+impl RoutingTable {
+    pub(crate) fn new(self_id: DhtId) -> Self {
+        Self::with_id_policy(self_id, IdPolicy::default())
+    }
+
+    pub(crate) fn with_id_policy(self_id: DhtId, id_policy: IdPolicy) -> Self {
+        let now = Instant::now();
+        Self {
+            self_id,
+            buckets: (0..BUCKETS_NUM).map(|_| Bucket::new(now)).collect(),
+            id_policy,
+        }
+    }
+
+    pub(crate) fn self_id(&self) -> &DhtId {
+        &self.self_id
+    }
+
+    /// Change our id, redistributing the known nodes between buckets.
+    /// Nodes that don't fit into their new buckets are dropped.
+    pub(crate) fn set_self_id(&mut self, self_id: DhtId) {
+        let nodes: Vec<Node> = self
+            .buckets
+            .iter_mut()
+            .flat_map(|bucket| bucket.nodes.drain(..))
+            .collect();
+        self.self_id = self_id;
+        for node in nodes {
+            if let Some(idx) = self.bucket_index(&node.contact.id) {
+                let bucket = &mut self.buckets[idx];
+                if bucket.nodes.len() < K {
+                    bucket.nodes.push(node);
+                }
+            }
+        }
+    }
+
+    /// Index of the bucket the id belongs to; `None` for our own id.
+    pub(crate) fn bucket_index(&self, id: &DhtId) -> Option<usize> {
+        let prefix = self.self_id.distance(id).leading_zeros() as usize;
+        if prefix < BUCKETS_NUM {
+            Some(prefix)
+        } else {
+            None
+        }
+    }
+
+    /// Record a reply to our query.
+    pub(crate) fn node_replied(&mut self, contact: Contact, now: Instant) -> InsertOutcome {
+        self.insert(contact, now, |node| {
+            node.last_reply = Some(now);
+            node.failed_queries = 0;
+        })
+    }
+
+    /// Record an incoming query.
+    pub(crate) fn node_queried(&mut self, contact: Contact, now: Instant) -> InsertOutcome {
+        self.insert(contact, now, |node| node.last_query = Some(now))
+    }
+
+    /// Record a query the node failed to respond to.
+    pub(crate) fn query_failed(&mut self, id: &DhtId) {
+        if let Some(node) = self.find_mut(id) {
+            node.failed_queries += 1;
+        }
+    }
+
+    pub(crate) fn remove(&mut self, id: &DhtId) -> Option<Contact> {
+        let idx = self.bucket_index(id)?;
+        let bucket = &mut self.buckets[idx];
+        let pos = bucket
+            .nodes
+            .iter()
+            .position(|node| &node.contact.id == id)?;
+        Some(bucket.nodes.remove(pos).contact)
+    }
+
+    #[cfg(test)]
+    pub(crate) fn status(&self, id: &DhtId, now: Instant) -> Option<NodeStatus> {
+        let bucket = &self.buckets[self.bucket_index(id)?];
+        bucket
+            .nodes
+            .iter()
+            .find(|node| &node.contact.id == id)
+            .map(|node| node.status(now))
+    }
+
+    /// Up to `count` non-bad contacts closest to the target, ordered by
+    /// distance.  Only nodes that have responded to us are verified:
+    /// anyone can send a query from a spoofed address.
+    pub(crate) fn closest(&self, target: &DhtId, count: usize, now: Instant) -> Vec<Contact> {
+        let mut nodes: Vec<&Contact> = self
+            .buckets
+            .iter()
+            .flat_map(|bucket| bucket.nodes.iter())
+            .filter(|node| node.last_reply.is_some() && node.status(now) != NodeStatus::Bad)
+            .map(|node| &node.contact)
+            .collect();
+        nodes.sort_by_cached_key(|contact| contact.id.distance(target));
+        nodes.into_iter().take(count).cloned().collect()
+    }
+
+    /// Buckets that weren't changed for the refresh period.
+    pub(crate) fn stale_buckets(&self, now: Instant) -> Vec<usize> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .filter(|(_, bucket)| {
+                !bucket.nodes.is_empty()
+                    && now.saturating_duration_since(bucket.last_changed) >= GOOD_NODE_PERIOD
+            })
+            .map(|(idx, _)| idx)
+            .collect()
+    }
+
+    /// Random id that falls into the bucket, as a target for its refresh.
+    pub(crate) fn random_id_in_bucket<R: Rng + CryptoRng>(&self, idx: usize, rng: &mut R) -> DhtId {
+        assert!(idx < BUCKETS_NUM);
+        let mut distance = DhtId::new(rng);
+        for (n, b) in distance.0.iter_mut().enumerate() {
+            let first_bit = n * 8;
+            if idx >= first_bit + 8 {
+                *b = 0;
+            } else if idx >= first_bit {
+                let shift = idx - first_bit;
+                // Clear the common prefix bits and set the first
+                // differing bit.
+                *b = (*b & (0xFF >> shift)) | (0x80 >> shift);
+            }
+        }
+        self.self_id.distance(&distance)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.nodes.len()).sum()
+    }
+
+    /// Number of good nodes, e.g. to tell whether the bootstrap is
+    /// done.
+    pub(crate) fn good_len(&self, now: Instant) -> usize {
+        self.buckets
+            .iter()
+            .flat_map(|bucket| bucket.nodes.iter())
+            .filter(|node| node.status(now) == NodeStatus::Good)
+            .count()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Non-bad contacts that have been seen, with the last time they
+    /// were, e.g. to save them.
+    pub(crate) fn seen_contacts(&self, now: Instant) -> Vec<(Contact, Instant)> {
+        self.buckets
+            .iter()
+            .flat_map(|bucket| bucket.nodes.iter())
+            .filter(|node| node.status(now) != NodeStatus::Bad)
+            .filter_map(|node| Some((node.contact.clone(), node.last_seen()?)))
+            .collect()
+    }
+
+    pub(crate) fn contacts(&self) -> impl Iterator<Item = &Contact> + '_ {
+        self.buckets
+            .iter()
+            .flat_map(|bucket| bucket.nodes.iter())
+            .map(|node| &node.contact)
+    }
+
+    fn find_mut(&mut self, id: &DhtId) -> Option<&mut Node> {
+        let idx = self.bucket_index(id)?;
+        self.buckets[idx]
+            .nodes
+            .iter_mut()
+            .find(|node| &node.contact.id == id)
+    }
+
+    fn insert<F: FnOnce(&mut Node)>(
+        &mut self,
+        contact: Contact,
+        now: Instant,
+        update: F,
+    ) -> InsertOutcome {
+        let idx = match self.bucket_index(&contact.id) {
+            Some(idx) => idx,
+            None => return InsertOutcome::Rejected,
+        };
+        let bucket = &mut self.buckets[idx];
+
+        if let Some(node) = bucket
+            .nodes
+            .iter_mut()
+            .find(|node| node.contact.id == contact.id)
+        {
+            if node.contact.addr != contact.addr {
+                return InsertOutcome::Rejected;
+            }
+            update(node);
+            bucket.last_changed = now;
+            return InsertOutcome::Updated;
+        }
+
+        let mut node = Node::new(contact);
+        update(&mut node);
+        let id_policy = self.id_policy;
+        if id_policy == IdPolicy::Require && !node.valid_id {
+            return InsertOutcome::Rejected;
+        }
+
+        if bucket.nodes.len() < K {
+            bucket.nodes.push(node);
+            bucket.last_changed = now;
+            return InsertOutcome::Added;
+        }
+
+        if let Some(bad) = bucket
+            .nodes
+            .iter_mut()
+            .find(|node| node.status(now) == NodeStatus::Bad)
+        {
+            let old = std::mem::replace(bad, node);
+            bucket.last_changed = now;
+            return InsertOutcome::Replaced(old.contact);
+        }
+
+        if id_policy == IdPolicy::Prefer {
+            if !node.valid_id {
+                return InsertOutcome::Discarded;
+            }
+            if let Some(invalid) = bucket.nodes.iter_mut().find(|node| !node.valid_id) {
+                let old = std::mem::replace(invalid, node);
+                bucket.last_changed = now;
+                return InsertOutcome::Replaced(old.contact);
+            }
+        }
+
+        bucket
+            .nodes
+            .iter_mut()
+            .filter(|node| node.status(now) == NodeStatus::Questionable)
+            .filter(|node| {
+                node.pinged.map_or(true, |pinged| {
+                    now.saturating_duration_since(pinged) >= PING_PERIOD
+                })
+            })
+            .min_by_key(|node| node.last_seen())
+            .map(|node| {
+                node.pinged = Some(now);
+                InsertOutcome::PingQuestionable(node.contact.clone())
+            })
+            .unwrap_or(InsertOutcome::Discarded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn id_with_bit(bit: usize) -> DhtId {
+        let mut id = DhtId::default();
+        id.0[bit / 8] = 0x80 >> (bit % 8);
+        id
+    }
+
+    fn contact(id: DhtId, port: u16) -> Contact {
+        Contact::new(id, SocketAddrV4::new(Ipv4Addr::LOCALHOST, port).into())
+    }
+
+    #[test]
+    fn test_bucket_index() {
+        let table = RoutingTable::new(DhtId::default());
+
+        assert_eq!(table.bucket_index(&DhtId::default()), None);
+        assert_eq!(table.bucket_index(&DhtId([0xFF; 20])), Some(0));
+        assert_eq!(table.bucket_index(&id_with_bit(0)), Some(0));
+        assert_eq!(table.bucket_index(&id_with_bit(1)), Some(1));
+        assert_eq!(table.bucket_index(&id_with_bit(9)), Some(9));
+        assert_eq!(table.bucket_index(&id_with_bit(159)), Some(159));
+    }
+
+    #[test]
+    fn test_bucket_index_nonzero_self() {
+        let table = RoutingTable::new(DhtId([0xFF; 20]));
+
+        assert_eq!(table.bucket_index(&DhtId([0xFF; 20])), None);
+        assert_eq!(table.bucket_index(&DhtId::default()), Some(0));
+        let mut id = DhtId([0xFF; 20]);
+        id.0[2] = 0xF7;
+        assert_eq!(table.bucket_index(&id), Some(20));
+    }
+
+    #[test]
+    fn test_full_bucket_discards() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(DhtId::default());
+
+        // All these ids go to the bucket 0.
+        for n in 0..K as u8 {
+            let mut id = id_with_bit(0);
+            id.0[19] = n;
+            assert_eq!(
+                table.node_replied(contact(id, n as u16), now),
+                InsertOutcome::Added
+            );
+        }
+        let mut id = id_with_bit(0);
+        id.0[19] = 0xFF;
+        assert_eq!(
+            table.node_replied(contact(id, 1000), now),
+            InsertOutcome::Discarded
+        );
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn test_bad_node_replaced() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(DhtId::default());
+
+        for n in 0..K as u8 {
+            let mut id = id_with_bit(0);
+            id.0[19] = n;
+            table.node_replied(contact(id, n as u16), now);
+        }
+        let mut bad = id_with_bit(0);
+        bad.0[19] = 3;
+        for _ in 0..MAX_FAILED_QUERIES {
+            table.query_failed(&bad);
+        }
+        assert_eq!(table.status(&bad, now), Some(NodeStatus::Bad));
+
+        let mut id = id_with_bit(0);
+        id.0[19] = 0xFF;
+        assert_eq!(
+            table.node_replied(contact(id.clone(), 1000), now),
+            InsertOutcome::Replaced(contact(bad.clone(), 3))
+        );
+        assert_eq!(table.status(&bad, now), None);
+        assert_eq!(table.status(&id, now), Some(NodeStatus::Good));
+    }
+
+    #[test]
+    fn test_questionable_node_pinged() {
+        let now = Instant::now();
+        let later = now + GOOD_NODE_PERIOD;
+        let mut table = RoutingTable::new(DhtId::default());
+
+        for n in 0..K as u8 {
+            let mut id = id_with_bit(0);
+            id.0[19] = n;
+            // Node 0 is seen earlier than the others.
+            let seen = if n == 0 { now } else { later };
+            table.node_replied(contact(id, n as u16), seen);
+        }
+        let mut id = id_with_bit(0);
+        id.0[19] = 0xFF;
+        let mut oldest = id_with_bit(0);
+        oldest.0[19] = 0;
+        assert_eq!(
+            table.node_replied(contact(id.clone(), 1000), later),
+            InsertOutcome::PingQuestionable(contact(oldest.clone(), 0))
+        );
+        // The node being pinged isn't offered again until the period
+        // is over.
+        assert_eq!(
+            table.node_replied(contact(id.clone(), 1000), later),
+            InsertOutcome::Discarded
+        );
+        assert_eq!(
+            table.node_replied(contact(id, 1000), later + PING_PERIOD),
+            InsertOutcome::PingQuestionable(contact(oldest, 0))
+        );
+        assert_eq!(table.good_len(now), K);
+        assert_eq!(table.good_len(later + Duration::from_secs(1)), K - 1);
+    }
+
+    #[test]
+    fn test_closest() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(DhtId::default());
+        for bit in 0..20 {
+            table.node_replied(contact(id_with_bit(bit), bit as u16), now);
+        }
+
+        let closest = table.closest(&id_with_bit(5), 3, now);
+        assert_eq!(
+            closest,
+            vec![
+                contact(id_with_bit(5), 5),
+                contact(id_with_bit(19), 19),
+                contact(id_with_bit(18), 18),
+            ]
+        );
+
+        // A node that has only queried us is not handed out.
+        let mut id = id_with_bit(5);
+        id.0[19] = 1;
+        table.node_queried(contact(id, 100), now);
+        assert_eq!(table.closest(&id_with_bit(5), 3, now), closest);
+    }
+
+    #[test]
+    fn test_seen_contacts() {
+        let now = Instant::now();
+        let later = now + Duration::from_secs(1);
+        let mut table = RoutingTable::new(DhtId::default());
+        table.node_replied(contact(id_with_bit(0), 0), now);
+        table.node_queried(contact(id_with_bit(1), 1), later);
+        table.node_replied(contact(id_with_bit(2), 2), now);
+        for _ in 0..MAX_FAILED_QUERIES {
+            table.query_failed(&id_with_bit(2));
+        }
+
+        let mut seen = table.seen_contacts(later);
+        seen.sort_by_key(|(contact, _)| contact.addr.port());
+        assert_eq!(
+            seen,
+            vec![
+                (contact(id_with_bit(0), 0), now),
+                (contact(id_with_bit(1), 1), later),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_known_id_other_addr_rejected() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(DhtId::default());
+
+        table.node_replied(contact(id_with_bit(3), 1), now);
+        assert_eq!(
+            table.node_queried(contact(id_with_bit(3), 2), now),
+            InsertOutcome::Rejected
+        );
+        assert_eq!(
+            table.node_queried(contact(id_with_bit(3), 1), now),
+            InsertOutcome::Updated
+        );
+    }
+
+    /// The first spec example of BEP 42.
+    fn valid_contact() -> Contact {
+        Contact::new(
+            DhtId::from_str("5fbfbff10c5d6a4ec8a88e4c6ab4c28b95eee401").unwrap(),
+            SocketAddrV4::new(Ipv4Addr::new(124, 31, 75, 21), 6881).into(),
+        )
+    }
+
+    /// Contacts with invalid ids in the bucket 0 of the `[0xFF; 20]`
+    /// table.
+    fn invalid_contact(n: u8) -> Contact {
+        let mut id = DhtId::default();
+        id.0[19] = n;
+        let contact = Contact::new(
+            id,
+            SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, n), 6881).into(),
+        );
+        assert!(!bep_0042::is_valid_id(&contact.id, contact.addr.ip()));
+        contact
+    }
+
+    #[test]
+    fn test_id_policy_require() {
+        let now = Instant::now();
+        let mut table = RoutingTable::with_id_policy(DhtId([0xFF; 20]), IdPolicy::Require);
+
+        assert_eq!(
+            table.node_replied(invalid_contact(1), now),
+            InsertOutcome::Rejected
+        );
+        assert_eq!(
+            table.node_replied(valid_contact(), now),
+            InsertOutcome::Added
+        );
+        // Local addresses are exempt.
+        assert_eq!(
+            table.node_replied(contact(id_with_bit(3), 1), now),
+            InsertOutcome::Added
+        );
+    }
+
+    #[test]
+    fn test_id_policy_prefer() {
+        let now = Instant::now();
+        let mut table = RoutingTable::with_id_policy(DhtId([0xFF; 20]), IdPolicy::Prefer);
+
+        for n in 0..K as u8 {
+            assert_eq!(
+                table.node_replied(invalid_contact(n), now),
+                InsertOutcome::Added
+            );
+        }
+        assert_eq!(
+            table.node_replied(invalid_contact(K as u8), now),
+            InsertOutcome::Discarded
+        );
+        assert_eq!(
+            table.node_replied(valid_contact(), now),
+            InsertOutcome::Replaced(invalid_contact(0))
+        );
+        assert_eq!(table.len(), K);
+    }
+
+    #[test]
+    fn test_set_self_id() {
+        let now = Instant::now();
+        let mut table = RoutingTable::new(DhtId::default());
+        table.node_replied(contact(id_with_bit(3), 1), now);
+        table.node_replied(contact(id_with_bit(5), 2), now);
+
+        // The new id collides with a known node, which is dropped.
+        table.set_self_id(id_with_bit(3));
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.bucket_index(&id_with_bit(5)), Some(3));
+        assert_eq!(table.status(&id_with_bit(5), now), Some(NodeStatus::Good));
+    }
+
+    #[test]
+    fn test_random_id_in_bucket() {
+        let mut rng = crate::dht::init_chacha();
+        let table = RoutingTable::new(DhtId::new(&mut rng));
+
+        for idx in [0, 1, 7, 8, 9, 100, 159].iter() {
+            let id = table.random_id_in_bucket(*idx, &mut rng);
+            assert_eq!(table.bucket_index(&id), Some(*idx));
+        }
+    }
+}
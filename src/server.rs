@@ -0,0 +1,928 @@
+//! Server side of the KRPC protocol: answers incoming queries.
+use crate::bencode;
+use crate::bep_0042;
+use crate::bep_0044::{self, ItemStore};
+use crate::dht;
+use crate::dht::{DhtId, Message, OutgoingMessage, Query};
+use crate::external_ip::ExternalIp;
+use crate::lookup;
+use crate::peer_store::{PeerStore, MAX_VALUES, MAX_VALUES6};
+use crate::query_queue::{QueryId, QueryQueue};
+use crate::routing_table::{Contact, InsertOutcome, RoutingTable, K};
+use crate::token::TokenManager;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::sync::Mutex as StdMutex;
+use std::time::Instant;
+use tokio::net::UdpSocket;
+use tokio::sync::watch;
+
+const KNOWN_METHODS: &[&str] = &[
+    "ping",
+    "find_node",
+    "get_peers",
+    "announce_peer",
+    "get",
+    "put",
+    "sample_infohashes",
+];
+
+/// Seconds a requester should wait before sending us another
+/// `sample_infohashes`, BEP 51.
+pub(crate) const SAMPLE_INTERVAL: u32 = 6 * 60 * 60;
+/// Info hashes in a `sample_infohashes` reply; keeps the reply within
+/// a single UDP packet with the nodes.
+const MAX_SAMPLES: usize = 20;
+
+// Used for telling an unknown method from a malformed query.
+#[derive(Deserialize)]
+struct QueryMethod<'msg> {
+    #[serde(borrow)]
+    q: &'msg str,
+}
+
+pub(crate) struct Server {
+    table: Arc<StdMutex<RoutingTable>>,
+    table6: Arc<StdMutex<RoutingTable>>,
+    tokens: StdMutex<TokenManager>,
+    peers: StdMutex<PeerStore>,
+    items: StdMutex<ItemStore>,
+    external_ip: StdMutex<ExternalIp>,
+    external_ip6: StdMutex<ExternalIp>,
+    /// Questionable nodes to ping, with the querying nodes waiting for
+    /// their places, BEP 5.
+    pings: StdMutex<Vec<(Contact, Contact, Instant)>>,
+    /// Our node id; it changes when the external address does.
+    id: watch::Sender<DhtId>,
+    /// Incoming queries are ignored in the read-only mode, BEP 43.
+    read_only: bool,
+}
+
+impl Server {
+    /// The server uses separate routing tables for IPv4 and IPv6
+    /// nodes, as BEP 32 prescribes.  Only the table of the bound
+    /// address family has our node `id`; the other one may be shared
+    /// with the node bound to that family.
+    pub(crate) fn new(
+        id: DhtId,
+        table: Arc<StdMutex<RoutingTable>>,
+        table6: Arc<StdMutex<RoutingTable>>,
+    ) -> Self {
+        let (id, _) = watch::channel(id);
+        Self {
+            table,
+            table6,
+            tokens: StdMutex::new(TokenManager::new(dht::init_chacha(), Instant::now())),
+            peers: Default::default(),
+            items: Default::default(),
+            external_ip: Default::default(),
+            external_ip6: Default::default(),
+            pings: Default::default(),
+            id,
+            read_only: false,
+        }
+    }
+
+    /// Run as a read-only node that doesn't answer queries.  The query
+    /// queue should be read-only too.
+    pub(crate) fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    fn self_id(&self) -> DhtId {
+        self.id.borrow().clone()
+    }
+
+    /// Watch node id changes, e.g. to persist them.
+    pub(crate) fn subscribe_id(&self) -> watch::Receiver<DhtId> {
+        self.id.subscribe()
+    }
+
+    /// External addresses voted by the responders, IPv4 and IPv6.
+    pub(crate) fn external_ip(&self) -> (Option<IpAddr>, Option<IpAddr>) {
+        (
+            self.external_ip.lock().unwrap().current(),
+            self.external_ip6.lock().unwrap().current(),
+        )
+    }
+
+    /// Count the responder's vote for our address.  The node id is
+    /// derived from the external address of the family of the bound
+    /// one, and it is regenerated when it doesn't match the new one.
+    pub(crate) fn vote_external_ip(&self, from: SocketAddr, reported: IpAddr) {
+        let external_ip = if from.is_ipv4() {
+            &self.external_ip
+        } else {
+            &self.external_ip6
+        };
+        let ip = match external_ip.lock().unwrap().vote(from.ip(), reported) {
+            Some(ip) => ip,
+            None => return,
+        };
+        if bep_0042::is_valid_id(&self.self_id(), ip) {
+            return;
+        }
+
+        let id = bep_0042::gen_self_id(ip, &mut dht::init_chacha());
+        // Votes come in on the socket of their family, whose table is
+        // ours.
+        self.table_for(&from)
+            .lock()
+            .unwrap()
+            .set_self_id(id.clone());
+        self.id.send_replace(id);
+    }
+
+    fn table_for(&self, addr: &SocketAddr) -> &Arc<StdMutex<RoutingTable>> {
+        if addr.is_ipv4() {
+            &self.table
+        } else {
+            &self.table6
+        }
+    }
+
+    /// Nodes closest to the target from the tables requested by `want`,
+    /// or from the table of the requester's address family by default.
+    fn closest_nodes(
+        &self,
+        target: &DhtId,
+        want: &Option<Vec<String>>,
+        from: &SocketAddr,
+        now: Instant,
+    ) -> (
+        Option<dht::CompactNodesList<'static>>,
+        Option<dht::CompactNodes6List<'static>>,
+    ) {
+        let wants = |family| {
+            want.as_ref()
+                .is_some_and(|want| want.iter().any(|w| w == family))
+        };
+        let (mut v4, mut v6) = (wants(dht::WANT_V4), wants(dht::WANT_V6));
+        if !v4 && !v6 {
+            v4 = from.is_ipv4();
+            v6 = !v4;
+        }
+
+        let nodes = if v4 {
+            let closest = self.table.lock().unwrap().closest(target, K, now);
+            Some(dht::CompactNodesList::from_contacts(&closest))
+        } else {
+            None
+        };
+        let nodes6 = if v6 {
+            let closest = self.table6.lock().unwrap().closest(target, K, now);
+            Some(dht::CompactNodes6List::from_contacts(&closest))
+        } else {
+            None
+        };
+        (nodes, nodes6)
+    }
+
+    /// Handle an incoming query with transaction id `t`, returning
+    /// the encoded reply.  `read_only` is the BEP 43 flag of the
+    /// querying node.
+    pub(crate) fn handle_query(
+        &self,
+        from: SocketAddr,
+        t: &[u8],
+        read_only: bool,
+        data: &[u8],
+    ) -> Option<Vec<u8>> {
+        let query = match serde_bencoded::from_bytes_auto::<Message<()>>(data) {
+            Ok(Message::Q(query)) => query,
+            Ok(_) => return None,
+            Err(_) => {
+                return match serde_bencoded::from_bytes_auto::<QueryMethod>(data) {
+                    Ok(method) if !KNOWN_METHODS.contains(&method.q) => {
+                        encode_error(t, from, dht::METHOD_UNKNOWN, "Method Unknown")
+                    }
+                    _ => encode_error(t, from, dht::PROTOCOL_ERROR, "Protocol Error"),
+                };
+            }
+        };
+
+        let now = Instant::now();
+        let contact = Contact::new(query.id().clone(), from);
+        let mut table = self.table_for(&from).lock().unwrap();
+        if read_only {
+            // Read-only nodes don't answer queries, so they are kept
+            // out of the routing table.
+            if table.contacts().any(|known| known == &contact) {
+                table.remove(&contact.id);
+            }
+        } else if let InsertOutcome::PingQuestionable(questionable) =
+            table.node_queried(contact.clone(), now)
+        {
+            self.pings
+                .lock()
+                .unwrap()
+                .push((questionable, contact, now));
+        }
+        drop(table);
+
+        let id = self.self_id();
+        match query {
+            Query::Ping(_) => encode_reply(t, from, dht::PingResponse { id }),
+            Query::FindNode(q) => {
+                let (nodes, nodes6) = self.closest_nodes(&q.target, &q.want, &from, now);
+                encode_reply(t, from, dht::FindNodeResponse { id, nodes, nodes6 })
+            }
+            Query::GetPeers(q) => {
+                let token = Cow::Owned(self.tokens.lock().unwrap().issue(from.ip(), now));
+                let max_values = if from.is_ipv4() {
+                    MAX_VALUES
+                } else {
+                    MAX_VALUES6
+                };
+                let mut r = dht::GetPeersResponse {
+                    id,
+                    token,
+                    values: None,
+                    nodes: None,
+                    nodes6: None,
+                    bf_sd: None,
+                    bf_pe: None,
+                };
+                if q.scrape == Some(1) {
+                    if let Some((seeds, downloaders)) =
+                        self.peers.lock().unwrap().scrape(&q.info_hash, now)
+                    {
+                        r.bf_sd = Some(seeds);
+                        r.bf_pe = Some(downloaders);
+                    }
+                } else {
+                    let values: Vec<dht::NodeAddr> = self
+                        .peers
+                        .lock()
+                        .unwrap()
+                        .get_peers(
+                            &q.info_hash,
+                            from.is_ipv6(),
+                            q.noseed == Some(1),
+                            max_values,
+                            now,
+                            &mut rand::thread_rng(),
+                        )
+                        .iter()
+                        .map(dht::NodeAddr::from)
+                        .collect();
+                    if !values.is_empty() {
+                        r.values = Some(values);
+                    }
+                }
+                // Nodes are returned only if we know no peers.
+                if r.values.is_none() && r.bf_sd.is_none() {
+                    let (nodes, nodes6) = self.closest_nodes(&q.info_hash, &q.want, &from, now);
+                    r.nodes = nodes;
+                    r.nodes6 = nodes6;
+                }
+                encode_reply(t, from, r)
+            }
+            Query::SampleInfohashes(q) => {
+                let (samples, num) = {
+                    let mut peers = self.peers.lock().unwrap();
+                    let samples =
+                        peers.sample_info_hashes(MAX_SAMPLES, now, &mut rand::thread_rng());
+                    (samples, peers.info_hashes_count())
+                };
+                let (nodes, nodes6) = self.closest_nodes(&q.target, &None, &from, now);
+                encode_reply(
+                    t,
+                    from,
+                    dht::SampleInfohashesResponse {
+                        id,
+                        interval: SAMPLE_INTERVAL,
+                        num: num as u32,
+                        samples: dht::InfoHashList::from_ids(&samples),
+                        nodes,
+                        nodes6,
+                    },
+                )
+            }
+            Query::AnnouncePeer(q) => {
+                if !self
+                    .tokens
+                    .lock()
+                    .unwrap()
+                    .validate(from.ip(), &q.token, now)
+                {
+                    return encode_error(t, from, dht::PROTOCOL_ERROR, "Bad Token");
+                }
+                let port = if q.implied_port != 0 {
+                    from.port()
+                } else {
+                    q.port
+                };
+                if !self.peers.lock().unwrap().announce(
+                    q.info_hash,
+                    SocketAddr::new(from.ip(), port),
+                    q.seed == Some(1),
+                    now,
+                ) {
+                    return encode_error(t, from, dht::SERVER_ERROR, "Too Many Peers");
+                }
+                encode_reply(t, from, dht::AnnouncePeerResponse { id })
+            }
+            Query::Get(q) => {
+                let token = Cow::Owned(self.tokens.lock().unwrap().issue(from.ip(), now));
+                let item = self.items.lock().unwrap().get(&q.target, now).cloned();
+                let (nodes, nodes6) = self.closest_nodes(&q.target, &None, &from, now);
+                let mut r = dht::GetResponse {
+                    id,
+                    token,
+                    v: None,
+                    k: None,
+                    sig: None,
+                    seq: None,
+                    nodes,
+                    nodes6,
+                };
+                match item {
+                    Some(bep_0044::Item::Immutable(v)) => r.v = Some(v),
+                    // The requester already has this or a newer version.
+                    Some(bep_0044::Item::Mutable(item)) if q.seq >= Some(item.seq) => {
+                        r.seq = Some(item.seq);
+                    }
+                    Some(bep_0044::Item::Mutable(item)) => {
+                        r.v = Some(item.v);
+                        r.k = Some(Cow::Owned(item.k.to_vec()));
+                        r.sig = Some(Cow::Owned(item.sig.to_vec()));
+                        r.seq = Some(item.seq);
+                    }
+                    None => {}
+                }
+                encode_reply(t, from, r)
+            }
+            Query::Put(q) => {
+                if !self
+                    .tokens
+                    .lock()
+                    .unwrap()
+                    .validate(from.ip(), &q.token, now)
+                {
+                    return encode_error(t, from, dht::PROTOCOL_ERROR, "Bad Token");
+                }
+                if q.v.encode().len() > bep_0044::MAX_VALUE_SIZE {
+                    return encode_error(
+                        t,
+                        from,
+                        bep_0044::VALUE_TOO_BIG,
+                        "Message (v field) too big",
+                    );
+                }
+                if q.k.is_none() {
+                    if !self.items.lock().unwrap().put_immutable(q.v, now) {
+                        return encode_error(t, from, dht::SERVER_ERROR, "Too Many Items");
+                    }
+                    return encode_reply(t, from, dht::PutResponse { id });
+                }
+                let cas = q.cas;
+                let res = match mutable_item(q) {
+                    Ok(item) => self.items.lock().unwrap().put_mutable(item, cas, now),
+                    Err((code, text)) => return encode_error(t, from, code, text),
+                };
+                match res {
+                    Ok(()) => encode_reply(t, from, dht::PutResponse { id }),
+                    Err(bep_0044::PutError::CasMismatch) => {
+                        encode_error(t, from, bep_0044::CAS_MISMATCH, "CAS mismatch")
+                    }
+                    Err(bep_0044::PutError::SeqTooOld) => encode_error(
+                        t,
+                        from,
+                        bep_0044::SEQ_TOO_OLD,
+                        "sequence number less than current",
+                    ),
+                    Err(bep_0044::PutError::Full) => {
+                        encode_error(t, from, dht::SERVER_ERROR, "Too Many Items")
+                    }
+                }
+            }
+        }
+    }
+
+    /// Receive loop: replies are passed to the query queue, and queries
+    /// are answered.
+    pub(crate) async fn run(&self, udp: Arc<UdpSocket>, queue: Arc<QueryQueue>) {
+        let mut data = vec![0u8; 1 << 16];
+        loop {
+            let (len, from) = match udp.recv_from(&mut data).await {
+                Ok(res) => res,
+                Err(e) => {
+                    eprintln!("WARNING: recv failed: {}", e);
+                    continue;
+                }
+            };
+            let packet = &data[..len];
+
+            let msg: dht::IncomingMessage = match serde_bencoded::from_bytes_auto(packet) {
+                Ok(msg) => msg,
+                Err(_) => {
+                    eprintln!("WARNING: malformed message from {}", from);
+                    continue;
+                }
+            };
+
+            match msg.y {
+                "r" | "e" => {
+                    // Our transaction ids are always two bytes.
+                    let solicited = match *msg.t {
+                        [a, b] => queue.got_reply(from, QueryId::from_be_bytes([a, b]), packet),
+                        _ => false,
+                    };
+                    // Only the replies to our queries vote, otherwise
+                    // anyone could move our address and node id.
+                    if let (true, Some(ip), "r") = (solicited, &msg.ip, msg.y) {
+                        self.vote_external_ip(from, ip.socket_addr().ip());
+                    }
+                }
+                "q" if self.read_only => {}
+                "q" => {
+                    let read_only = msg.ro.unwrap_or(false);
+                    if let Some(reply) = self.handle_query(from, msg.t, read_only, packet) {
+                        if let Err(e) = udp.send_to(&reply, from).await {
+                            eprintln!("WARNING: failed to reply to {}: {}", from, e);
+                        }
+                    }
+                    let pings = std::mem::take(&mut *self.pings.lock().unwrap());
+                    for (questionable, contact, seen) in pings {
+                        lookup::replace_if_dead(
+                            queue.clone(),
+                            udp.clone(),
+                            self.table_for(&contact.addr).clone(),
+                            questionable,
+                            contact,
+                            seen,
+                            RoutingTable::node_queried,
+                        );
+                    }
+                }
+                y => eprintln!("WARNING: ignoring message with y={}", y),
+            }
+        }
+    }
+}
+
+/// Mutable item of a `put` query with `k`, if the query is valid.
+fn mutable_item(q: dht::PutQuery) -> Result<bep_0044::MutableItem, (dht::ErrorKind, &'static str)> {
+    let mut k = [0; bep_0044::PUBLIC_KEY_SIZE];
+    let mut sig = [0; bep_0044::SIGNATURE_SIZE];
+    match (&q.k, &q.sig, q.seq) {
+        (Some(qk), Some(qsig), Some(_)) if qk.len() == k.len() && qsig.len() == sig.len() => {
+            k.copy_from_slice(qk);
+            sig.copy_from_slice(qsig);
+        }
+        _ => return Err((dht::PROTOCOL_ERROR, "Protocol Error")),
+    }
+    let salt = q.salt.map(Cow::into_owned).unwrap_or_default();
+    if salt.len() > bep_0044::MAX_SALT_SIZE {
+        return Err((bep_0044::SALT_TOO_BIG, "salt (salt field) too big"));
+    }
+    let item = bep_0044::MutableItem {
+        k,
+        sig,
+        seq: q.seq.unwrap_or_default(),
+        salt,
+        v: q.v,
+    };
+    if !item.verify() {
+        return Err((bep_0044::INVALID_SIGNATURE, "invalid signature"));
+    }
+    Ok(item)
+}
+
+fn encode_reply<R: Serialize>(t: &[u8], from: SocketAddr, r: R) -> Option<Vec<u8>> {
+    bencode::to_vec(&OutgoingMessage {
+        t: Cow::Borrowed(t),
+        ip: Some(dht::NodeAddr::from(&from)),
+        ro: None,
+        msg: Message::R { r },
+    })
+    .ok()
+}
+
+fn encode_error(t: &[u8], from: SocketAddr, code: dht::ErrorKind, text: &str) -> Option<Vec<u8>> {
+    serde_bencoded::to_vec(&OutgoingMessage::<()> {
+        t: Cow::Borrowed(t),
+        ip: Some(dht::NodeAddr::from(&from)),
+        ro: None,
+        msg: Message::E {
+            e: (code, text.to_owned()),
+        },
+    })
+    .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+    use std::net::{Ipv4Addr, SocketAddrV4};
+
+    fn server() -> Server {
+        let id = DhtId(*b"mnopqrstuvwxyz123456");
+        let table = RoutingTable::new(id.clone());
+        let table6 = RoutingTable::new(id.clone());
+        Server::new(
+            id,
+            Arc::new(StdMutex::new(table)),
+            Arc::new(StdMutex::new(table6)),
+        )
+    }
+
+    fn from() -> SocketAddr {
+        SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 6881).into()
+    }
+
+    #[test]
+    fn test_ping_reply() {
+        const DATA: &[u8] = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:aa1:y1:qe";
+        let reply = server().handle_query(from(), b"aa", false, DATA).unwrap();
+        assert_eq!(
+            &reply[..],
+            &b"d2:ip6:\x0a\x00\x00\x01\x1a\xe11:rd2:id20:mnopqrstuvwxyz123456e1:t2:aa1:y1:re"[..]
+        );
+    }
+
+    #[test]
+    fn test_external_ip_vote() {
+        let server = server();
+        let mut id_changes = server.subscribe_id();
+        let external: IpAddr = [124, 31, 75, 21].into();
+
+        for n in 1..=3 {
+            server.vote_external_ip(
+                SocketAddrV4::new(Ipv4Addr::new(8, 8, 8, n), 6881).into(),
+                external,
+            );
+        }
+
+        assert_eq!(server.external_ip(), (Some(external), None));
+        assert!(id_changes.has_changed().unwrap());
+        let id = id_changes.borrow_and_update().clone();
+        assert!(bep_0042::is_valid_id(&id, external));
+        assert_eq!(server.table.lock().unwrap().self_id(), &id);
+        // The IPv6 table may be the one of another node.
+        assert_ne!(server.table6.lock().unwrap().self_id(), &id);
+
+        // The id of an IPv6 node is derived from its IPv6 address.
+        let server = self::server();
+        let mut id_changes = server.subscribe_id();
+        let external: IpAddr = "2001:4860::1".parse().unwrap();
+        for n in 1..=3 {
+            let from: IpAddr = format!("2001:db8::{}", n).parse().unwrap();
+            server.vote_external_ip(SocketAddr::new(from, 6881), external);
+        }
+        assert_eq!(server.external_ip(), (None, Some(external)));
+        assert!(id_changes.has_changed().unwrap());
+        let id = id_changes.borrow_and_update().clone();
+        assert!(bep_0042::is_valid_id(&id, external));
+        assert_eq!(server.table6.lock().unwrap().self_id(), &id);
+        assert_ne!(server.table.lock().unwrap().self_id(), &id);
+    }
+
+    #[tokio::test]
+    async fn test_unsolicited_reply_vote() {
+        let server = Arc::new(server());
+        let udp = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let server_addr = udp.local_addr().unwrap();
+        {
+            let server = server.clone();
+            let queue = Arc::new(QueryQueue::new(Default::default()));
+            tokio::spawn(async move { server.run(udp, queue).await });
+        }
+
+        const REPLY: &[u8] =
+            b"d2:ip6:\x7c\x1f\x4b\x15\x1a\xe11:rd2:id20:abcdefghij0123456789e1:t2:aa1:y1:re";
+        const PING: &[u8] = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping1:t2:bb1:y1:qe";
+        let mut buf = [0; 1500];
+        for n in 2..=4 {
+            let peer = UdpSocket::bind((Ipv4Addr::new(127, 0, 0, n), 0))
+                .await
+                .unwrap();
+            peer.send_to(REPLY, server_addr).await.unwrap();
+            // The reply to the ping comes after the reply is handled.
+            peer.send_to(PING, server_addr).await.unwrap();
+            peer.recv_from(&mut buf).await.unwrap();
+        }
+        assert_eq!(server.external_ip(), (None, None));
+    }
+
+    #[test]
+    fn test_find_node_reply() -> Result<(), Box<dyn Error>> {
+        const DATA: &[u8] = b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q9:find_node1:t2:aa1:y1:qe";
+        let server = server();
+        let reply = server.handle_query(from(), b"aa", false, DATA).unwrap();
+        // The querying node is in the table now, yet it isn't verified.
+        let msg: Message<dht::FindNodeResponse> = serde_bencoded::from_bytes_auto(&reply)?;
+        match msg {
+            Message::R { r } => assert_eq!(r.nodes.unwrap().iter().count(), 0),
+            _ => panic!("unexpected message {:?}", msg),
+        }
+        // Once it replies to us, it is returned.
+        let contact = Contact::new(DhtId(*b"abcdefghij0123456789"), from());
+        server
+            .table
+            .lock()
+            .unwrap()
+            .node_replied(contact, Instant::now());
+        let reply = server.handle_query(from(), b"bb", false, DATA).unwrap();
+        let msg: Message<dht::FindNodeResponse> = serde_bencoded::from_bytes_auto(&reply)?;
+        match msg {
+            Message::R { r } => {
+                assert_eq!(r.nodes6, None);
+                let nodes: Vec<_> = r.nodes.as_ref().unwrap().iter().collect();
+                assert_eq!(nodes.len(), 1);
+                assert_eq!(nodes[0].id, DhtId(*b"abcdefghij0123456789"));
+                assert_eq!(nodes[0].socket_addr(), from());
+            }
+            _ => panic!("unexpected message {:?}", msg),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_announce_token() -> Result<(), Box<dyn Error>> {
+        const GET_PEERS: &[u8] = b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz123456e1:q9:get_peers1:t2:aa1:y1:qe";
+        let server = server();
+
+        let reply = server
+            .handle_query(from(), b"aa", false, GET_PEERS)
+            .unwrap();
+        let msg: Message<dht::GetPeersResponse> = serde_bencoded::from_bytes_auto(&reply)?;
+        let token = match msg {
+            Message::R { r } => r.token.into_owned(),
+            _ => panic!("unexpected message {:?}", msg),
+        };
+
+        let announce = |token: &[u8]| {
+            let query = Message::<()>::Q(Query::AnnouncePeer(dht::AnnouncePeerQuery {
+                id: DhtId(*b"abcdefghij0123456789"),
+                info_hash: DhtId(*b"mnopqrstuvwxyz123456"),
+                token: Cow::Owned(token.to_vec()),
+                port: 6881,
+                implied_port: 0,
+                seed: None,
+            }));
+            let data = serde_bencoded::to_vec(&OutgoingMessage {
+                t: Cow::Borrowed(b"bb"),
+                ip: None,
+                ro: None,
+                msg: query,
+            })
+            .unwrap();
+            server.handle_query(from(), b"bb", false, &data).unwrap()
+        };
+
+        let reply = announce(&token);
+        let msg: Message<dht::AnnouncePeerResponse> = serde_bencoded::from_bytes_auto(&reply)?;
+        assert!(matches!(msg, Message::R { .. }));
+
+        let reply = server
+            .handle_query(from(), b"cc", false, GET_PEERS)
+            .unwrap();
+        let msg: Message<dht::GetPeersResponse> = serde_bencoded::from_bytes_auto(&reply)?;
+        match msg {
+            Message::R { r } => {
+                let values = r.values.unwrap();
+                assert_eq!(values.len(), 1);
+                assert_eq!(values[0].socket_addr(), from());
+                assert_eq!(r.nodes, None);
+            }
+            _ => panic!("unexpected message {:?}", msg),
+        }
+
+        let reply = announce(b"aoeusnth");
+        let msg: Message<dht::AnnouncePeerResponse> = serde_bencoded::from_bytes_auto(&reply)?;
+        assert!(matches!(msg, Message::E { e: (203, _) }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scrape() -> Result<(), Box<dyn Error>> {
+        const SCRAPE: &[u8] = b"d1:ad2:id20:abcdefghij01234567899:info_hash20:mnopqrstuvwxyz1234566:scrapei1ee1:q9:get_peers1:t2:aa1:y1:qe";
+        let server = server();
+        let now = Instant::now();
+        let info_hash = DhtId(*b"mnopqrstuvwxyz123456");
+        {
+            let mut peers = server.peers.lock().unwrap();
+            peers.announce(info_hash.clone(), "10.0.0.1:1".parse()?, true, now);
+            peers.announce(info_hash.clone(), "10.0.0.2:1".parse()?, false, now);
+            peers.announce(info_hash, "10.0.0.3:1".parse()?, false, now);
+        }
+
+        let reply = server.handle_query(from(), b"aa", false, SCRAPE).unwrap();
+        let msg: Message<dht::GetPeersResponse> = serde_bencoded::from_bytes_auto(&reply)?;
+        match msg {
+            Message::R { r } => {
+                assert_eq!(r.bf_sd.unwrap().estimate().round(), 1.0);
+                assert_eq!(r.bf_pe.unwrap().estimate().round(), 2.0);
+                assert_eq!(r.values, None);
+                assert_eq!(r.nodes, None);
+            }
+            _ => panic!("unexpected message {:?}", msg),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_sample_infohashes() -> Result<(), Box<dyn Error>> {
+        const SAMPLE: &[u8] = b"d1:ad2:id20:abcdefghij01234567896:target20:mnopqrstuvwxyz123456e1:q17:sample_infohashes1:t2:aa1:y1:qe";
+        let server = server();
+        let now = Instant::now();
+        for b in 0..(MAX_SAMPLES as u8 + 5) {
+            server
+                .peers
+                .lock()
+                .unwrap()
+                .announce(DhtId([b; 20]), from(), false, now);
+        }
+
+        let reply = server.handle_query(from(), b"aa", false, SAMPLE).unwrap();
+        let msg: Message<dht::SampleInfohashesResponse> = serde_bencoded::from_bytes_auto(&reply)?;
+        match msg {
+            Message::R { r } => {
+                assert_eq!(r.interval, SAMPLE_INTERVAL);
+                assert_eq!(r.num, MAX_SAMPLES as u32 + 5);
+                assert_eq!(r.samples.iter().count(), MAX_SAMPLES);
+                assert!(r.nodes.is_some());
+            }
+            _ => panic!("unexpected message {:?}", msg),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_put_get_immutable() -> Result<(), Box<dyn Error>> {
+        const GET: &[u8] = b"d1:ad2:id20:abcdefghij01234567896:target20:\xe5\xf9\x6f\x6f\x38\x32\x0f\x0f\x33\x95\x9c\xb4\xd3\xd6\x56\x45\x21\x17\xaa\xdbe1:q3:get1:t2:aa1:y1:qe";
+        let server = server();
+
+        let reply = server.handle_query(from(), b"aa", false, GET).unwrap();
+        let msg: Message<dht::GetResponse> = serde_bencoded::from_bytes_auto(&reply)?;
+        let token = match msg {
+            Message::R { r } => {
+                assert_eq!(r.v, None);
+                r.token.into_owned()
+            }
+            _ => panic!("unexpected message {:?}", msg),
+        };
+
+        let put = |v: bep_0044::Value| {
+            let query = Message::<()>::Q(Query::Put(dht::PutQuery::immutable(
+                DhtId(*b"abcdefghij0123456789"),
+                Cow::Owned(token.clone()),
+                v,
+            )));
+            let data = serde_bencoded::to_vec(&OutgoingMessage {
+                t: Cow::Borrowed(b"bb"),
+                ip: None,
+                ro: None,
+                msg: query,
+            })
+            .unwrap();
+            server.handle_query(from(), b"bb", false, &data).unwrap()
+        };
+
+        let reply = put(bep_0044::Value::Bytes(vec![0; bep_0044::MAX_VALUE_SIZE]));
+        let msg: Message<dht::PutResponse> = serde_bencoded::from_bytes_auto(&reply)?;
+        assert!(matches!(msg, Message::E { e: (205, _) }));
+
+        let v = bep_0044::Value::Bytes(b"Hello World!".to_vec());
+        let reply = put(v.clone());
+        let msg: Message<dht::PutResponse> = serde_bencoded::from_bytes_auto(&reply)?;
+        assert!(matches!(msg, Message::R { .. }));
+
+        let reply = server.handle_query(from(), b"cc", false, GET).unwrap();
+        let msg: Message<dht::GetResponse> = serde_bencoded::from_bytes_auto(&reply)?;
+        match msg {
+            Message::R { r } => assert_eq!(r.v, Some(v)),
+            _ => panic!("unexpected message {:?}", msg),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_put_mutable_errors() -> Result<(), Box<dyn Error>> {
+        let server = server();
+        let token = server
+            .tokens
+            .lock()
+            .unwrap()
+            .issue(from().ip(), Instant::now());
+        let keypair = ed25519_dalek::Keypair::generate(&mut dht::init_chacha());
+        let put = |item: bep_0044::MutableItem, cas| {
+            let query = Message::<()>::Q(Query::Put(dht::PutQuery::mutable(
+                DhtId(*b"abcdefghij0123456789"),
+                Cow::Owned(token.clone()),
+                item,
+                cas,
+            )));
+            let data = serde_bencoded::to_vec(&OutgoingMessage {
+                t: Cow::Borrowed(b"aa"),
+                ip: None,
+                ro: None,
+                msg: query,
+            })
+            .unwrap();
+            let reply = server.handle_query(from(), b"aa", false, &data).unwrap();
+            let msg: Message<dht::PutResponse> = serde_bencoded::from_bytes_auto(&reply).unwrap();
+            match msg {
+                Message::R { .. } => 0,
+                Message::E { e: (code, _) } => code,
+                msg => panic!("unexpected message {:?}", msg),
+            }
+        };
+        let item = |seq| {
+            bep_0044::MutableItem::new(&keypair, b"salt".to_vec(), seq, bep_0044::Value::Int(seq))
+        };
+
+        assert_eq!(put(item(2), None), 0);
+        assert_eq!(put(item(1), None), bep_0044::SEQ_TOO_OLD);
+        assert_eq!(put(item(3), Some(1)), bep_0044::CAS_MISMATCH);
+        let mut forged = item(3);
+        forged.v = bep_0044::Value::Int(4);
+        assert_eq!(put(forged, None), bep_0044::INVALID_SIGNATURE);
+        let long_salt = bep_0044::MutableItem::new(
+            &keypair,
+            vec![0; bep_0044::MAX_SALT_SIZE + 1],
+            1,
+            bep_0044::Value::Int(1),
+        );
+        assert_eq!(put(long_salt, None), bep_0044::SALT_TOO_BIG);
+        assert_eq!(put(item(3), Some(2)), 0);
+
+        // Only newer versions are returned with `seq`.
+        let item3 = item(3);
+        let get = |seq| {
+            let query = Message::<()>::Q(Query::Get(dht::GetQuery {
+                id: DhtId(*b"abcdefghij0123456789"),
+                target: item3.target(),
+                seq,
+            }));
+            let data = serde_bencoded::to_vec(&OutgoingMessage {
+                t: Cow::Borrowed(b"bb"),
+                ip: None,
+                ro: None,
+                msg: query,
+            })
+            .unwrap();
+            server.handle_query(from(), b"bb", false, &data).unwrap()
+        };
+        let reply = get(None);
+        let msg: Message<dht::GetResponse> = serde_bencoded::from_bytes_auto(&reply)?;
+        match msg {
+            Message::R { r } => {
+                assert_eq!(r.v, Some(item3.v.clone()));
+                assert_eq!(r.seq, Some(3));
+                assert_eq!(r.sig.as_deref(), Some(&item3.sig[..]));
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        let reply = get(Some(3));
+        let msg: Message<dht::GetResponse> = serde_bencoded::from_bytes_auto(&reply)?;
+        match msg {
+            Message::R { r } => {
+                assert_eq!(r.v, None);
+                assert_eq!(r.seq, Some(3));
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_only_node_not_added() {
+        const DATA: &[u8] = b"d1:ad2:id20:abcdefghij0123456789e1:q4:ping2:roi1e1:t2:aa1:y1:qe";
+        let server = server();
+
+        // Read-only nodes still get replies.
+        assert!(server.handle_query(from(), b"aa", true, DATA).is_some());
+        assert!(server.table.lock().unwrap().is_empty());
+
+        // A known node that became read-only is removed.
+        server.handle_query(from(), b"bb", false, DATA).unwrap();
+        assert_eq!(server.table.lock().unwrap().len(), 1);
+        server.handle_query(from(), b"cc", true, DATA).unwrap();
+        assert!(server.table.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_unknown_method() -> Result<(), Box<dyn Error>> {
+        const DATA: &[u8] = b"d1:ad2:id20:abcdefghij0123456789e1:q4:pong1:t2:aa1:y1:qe";
+        let reply = server().handle_query(from(), b"aa", false, DATA).unwrap();
+        let msg: Message<dht::PingResponse> = serde_bencoded::from_bytes_auto(&reply)?;
+        assert!(matches!(msg, Message::E { e: (204, _) }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_query() -> Result<(), Box<dyn Error>> {
+        const DATA: &[u8] = b"d1:ad2:id3:abce1:q4:ping1:t2:aa1:y1:qe";
+        let reply = server().handle_query(from(), b"aa", false, DATA).unwrap();
+        let msg: Message<dht::PingResponse> = serde_bencoded::from_bytes_auto(&reply)?;
+        assert!(matches!(msg, Message::E { e: (203, _) }));
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+//! Tokens for `get_peers` and `announce_peer`, as described in
+//! https://www.bittorrent.org/beps/bep_0005.html
+//!
+//! A token is SHA-1 of the requester IP and a secret that changes
+//! every five minutes.  Tokens made with the previous secret are
+//! accepted too.
+use rand::RngCore;
+use rand_chacha::ChaCha20Rng;
+use sha1::{Digest, Sha1};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+const SECRET_SIZE: usize = 16;
+const ROTATION_PERIOD: Duration = Duration::from_secs(5 * 60);
+/// Tokens are truncated SHA-1 hashes.
+const TOKEN_SIZE: usize = 8;
+
+type Secret = [u8; SECRET_SIZE];
+
+pub(crate) struct TokenManager {
+    rng: ChaCha20Rng,
+    current: Secret,
+    previous: Secret,
+    rotated_at: Instant,
+}
+
+impl TokenManager {
+    pub(crate) fn new(mut rng: ChaCha20Rng, now: Instant) -> Self {
+        let mut current: Secret = Default::default();
+        rng.fill_bytes(&mut current);
+        let mut previous: Secret = Default::default();
+        rng.fill_bytes(&mut previous);
+        Self {
+            rng,
+            current,
+            previous,
+            rotated_at: now,
+        }
+    }
+
+    fn rotate(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.rotated_at);
+        if elapsed >= 2 * ROTATION_PERIOD {
+            // Both secrets are expired.
+            self.rng.fill_bytes(&mut self.previous);
+            self.rng.fill_bytes(&mut self.current);
+            self.rotated_at = now;
+        } else if elapsed >= ROTATION_PERIOD {
+            self.previous = self.current;
+            self.rng.fill_bytes(&mut self.current);
+            self.rotated_at += ROTATION_PERIOD;
+        }
+    }
+
+    /// Token for `get_peers` response to the `ip`.
+    pub(crate) fn issue(&mut self, ip: IpAddr, now: Instant) -> Vec<u8> {
+        self.rotate(now);
+        make_token(&self.current, ip)
+    }
+
+    /// Check token of `announce_peer` query from the `ip`.
+    pub(crate) fn validate(&mut self, ip: IpAddr, token: &[u8], now: Instant) -> bool {
+        self.rotate(now);
+        token == &make_token(&self.current, ip)[..] || token == &make_token(&self.previous, ip)[..]
+    }
+}
+
+fn make_token(secret: &Secret, ip: IpAddr) -> Vec<u8> {
+    let mut hasher = Sha1::new();
+    match ip {
+        IpAddr::V4(v4) => hasher.update(v4.octets()),
+        IpAddr::V6(v6) => hasher.update(v6.octets()),
+    }
+    hasher.update(secret);
+    hasher.finalize()[..TOKEN_SIZE].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const IP: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+    #[test]
+    fn test_validate() {
+        let now = Instant::now();
+        let mut tokens = TokenManager::new(crate::dht::init_chacha(), now);
+
+        let token = tokens.issue(IP, now);
+        assert_eq!(token.len(), TOKEN_SIZE);
+        assert!(tokens.validate(IP, &token, now));
+        assert!(!tokens.validate(Ipv4Addr::new(10, 0, 0, 2).into(), &token, now));
+        assert!(!tokens.validate(IP, b"aoeusnth", now));
+    }
+
+    #[test]
+    fn test_rotation() {
+        let now = Instant::now();
+        let mut tokens = TokenManager::new(crate::dht::init_chacha(), now);
+        let token = tokens.issue(IP, now);
+
+        let later = now + ROTATION_PERIOD;
+        assert_ne!(tokens.issue(IP, later), token);
+        // The previous secret is still accepted.
+        assert!(tokens.validate(IP, &token, later));
+
+        let much_later = later + ROTATION_PERIOD;
+        assert!(!tokens.validate(IP, &token, much_later));
+    }
+
+    #[test]
+    fn test_long_idle_expires_both() {
+        let now = Instant::now();
+        let mut tokens = TokenManager::new(crate::dht::init_chacha(), now);
+        let token = tokens.issue(IP, now);
+
+        assert!(!tokens.validate(IP, &token, now + 3 * ROTATION_PERIOD));
+    }
+}